@@ -0,0 +1,120 @@
+use serde::Deserialize;
+
+use crate::net::Net;
+use crate::rule::Failure;
+
+/// Failure fields naming a user, checked against `users`.
+const USER_FIELDS: &[&str] = &["user", "ruser", "logname"];
+
+/// Trusted users, hosts and networks whose failures are never counted.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Allowlist {
+    /// Matched against the `user`, `ruser` and `logname` fields.
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// Matched against `rhost` when it is a host name, ignoring case.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// Matched against `rhost` when it is an address.
+    #[serde(default)]
+    pub networks: Vec<Net>,
+}
+
+impl Allowlist {
+    /// Add the entries of `other`.
+    pub fn extend(&mut self, other: &Allowlist) {
+        self.users.extend(other.users.iter().cloned());
+        self.hosts.extend(other.hosts.iter().cloned());
+        self.networks.extend(other.networks.iter().copied());
+    }
+
+    pub fn allows(&self, failure: &Failure) -> bool {
+        let field = |name: &str| failure.fields.get(name).map_or("", String::as_str);
+
+        USER_FIELDS
+            .iter()
+            .map(|name| field(name))
+            .any(|user| !user.is_empty() && self.users.iter().any(|u| u == user))
+            || match failure.addr {
+                Some(addr) => self.networks.iter().any(|net| net.contains(addr)),
+                None => {
+                    let rhost = field("rhost");
+                    !rhost.is_empty() && self.hosts.iter().any(|h| h.eq_ignore_ascii_case(rhost))
+                }
+            }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rule::{Auth, AuthFailure};
+
+    fn failure(rule: &AuthFailure, user: &str, rhost: &str) -> Failure {
+        let line = format!(
+            "Mar  1 10:00:00 host sshd[42]: Failed password for {} from {} port 4242 ssh2",
+            user, rhost
+        );
+        rule.matches(&line).unwrap()
+    }
+
+    fn allowlist(users: &[&str], hosts: &[&str], networks: &[&str]) -> Allowlist {
+        Allowlist {
+            users: users.iter().map(|u| u.to_string()).collect(),
+            hosts: hosts.iter().map(|h| h.to_string()).collect(),
+            networks: networks.iter().map(|n| n.parse().unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn networks_only_for_addresses() {
+        let rule = AuthFailure::builtin(Auth::SshdFailedPassword);
+        let allow = allowlist(&[], &[], &["192.0.2.0/24", "2001:db8::/32"]);
+        assert!(allow.allows(&failure(&rule, "root", "192.0.2.7")));
+        assert!(allow.allows(&failure(&rule, "root", "2001:db8::1")));
+        assert!(allow.allows(&failure(&rule, "root", "::ffff:192.0.2.7")));
+        assert!(!allow.allows(&failure(&rule, "root", "198.51.100.7")));
+        // a host name that looks like a network entry is not an address
+        let mut by_name = failure(&rule, "root", "192.0.2.0");
+        by_name.addr = None;
+        assert!(!allow.allows(&by_name));
+    }
+
+    #[test]
+    fn hosts_only_for_names() {
+        let rule = AuthFailure::builtin(Auth::SshdFailedPassword);
+        let allow = allowlist(&[], &["bastion.example.org", "192.0.2.7"], &[]);
+        assert!(allow.allows(&failure(&rule, "root", "Bastion.Example.org")));
+        assert!(!allow.allows(&failure(&rule, "root", "other.example.org")));
+        // addresses are only matched against networks
+        assert!(!allow.allows(&failure(&rule, "root", "192.0.2.7")));
+    }
+
+    #[test]
+    fn rule_and_global_entries() {
+        let rule = AuthFailure::builtin(Auth::SshdFailedPassword);
+        let mut allow = allowlist(&["deploy"], &[], &[]);
+        allow.extend(&allowlist(
+            &["backup"],
+            &["bastion.example.org"],
+            &["10.0.0.0/8"],
+        ));
+        assert!(allow.allows(&failure(&rule, "deploy", "198.51.100.7")));
+        assert!(allow.allows(&failure(&rule, "backup", "198.51.100.7")));
+        assert!(allow.allows(&failure(&rule, "root", "bastion.example.org")));
+        assert!(allow.allows(&failure(&rule, "root", "10.1.2.3")));
+        assert!(!allow.allows(&failure(&rule, "root", "198.51.100.7")));
+    }
+
+    #[test]
+    fn grouped_rhost_checked_by_address() {
+        let mut rule = AuthFailure::builtin(Auth::SshdFailedPassword);
+        rule.ipv4_prefix = Some(16);
+        let failure = failure(&rule, "root", "192.0.2.7");
+        assert_eq!(failure.fields["rhost"], "192.0.0.0/16");
+        // the prefix reaches beyond the allowed network, the address does not
+        assert!(allowlist(&[], &[], &["192.0.2.0/24"]).allows(&failure));
+        assert!(!allowlist(&[], &[], &["192.0.3.0/24"]).allows(&failure));
+    }
+}
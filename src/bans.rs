@@ -0,0 +1,123 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::net::Net;
+use crate::state::write_atomic;
+
+pub const BANS_PATH: &str = "/var/lib/watch/bans.toml";
+
+/// A single ban, as listed by `watch bans list`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Ban {
+    /// Address or prefix.
+    pub addr: Net,
+    /// Name of the rule that caused the ban.
+    pub rule: String,
+    /// Message of the alert that caused the ban.
+    pub reason: String,
+    pub start: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Content {
+    #[serde(default, rename = "ban", skip_serializing_if = "Vec::is_empty")]
+    bans: Vec<Ban>,
+}
+
+/// Bans kept on disk, shared by the daemon and `watch bans`.
+///
+/// Whoever changes the file rewrites it right away, the daemon picks up
+/// changes made by `watch bans` through `refresh`.
+#[derive(Debug)]
+pub struct BanStore {
+    path: PathBuf,
+    pub bans: Vec<Ban>,
+    /// Modification time of the file when it was last read or written.
+    modified: Option<SystemTime>,
+}
+
+impl BanStore {
+    /// Read the ban file, a missing file has no bans.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, io::Error> {
+        let mut store = BanStore {
+            path: path.as_ref().to_path_buf(),
+            bans: vec![],
+            modified: None,
+        };
+        store.read()?;
+
+        Ok(store)
+    }
+
+    /// Read the file again if someone else changed it.
+    pub fn refresh(&mut self) -> Result<(), io::Error> {
+        if self.mtime() != self.modified {
+            self.read()?;
+        }
+
+        Ok(())
+    }
+
+    /// Replace the ban file atomically.
+    pub fn save(&mut self) -> Result<(), io::Error> {
+        let content = Content {
+            bans: self.bans.clone(),
+        };
+        let content = toml::to_string(&content)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+        write_atomic(&self.path, &content)?;
+        self.modified = self.mtime();
+
+        Ok(())
+    }
+
+    pub fn get(&self, addr: Net) -> Option<&Ban> {
+        self.bans.iter().find(|b| b.addr == addr)
+    }
+
+    /// Forget the ban of `addr`, returns it if there was one.
+    pub fn remove(&mut self, addr: Net) -> Option<Ban> {
+        let i = self.bans.iter().position(|b| b.addr == addr)?;
+        Some(self.bans.remove(i))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn read(&mut self) -> Result<(), io::Error> {
+        self.modified = self.mtime();
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.bans = vec![];
+                return Ok(());
+            }
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("{}: {}", self.path.display(), e),
+                ))
+            }
+        };
+        let content: Content = toml::from_str(&content).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", self.path.display(), e),
+            )
+        })?;
+        self.bans = content.bans;
+
+        Ok(())
+    }
+
+    fn mtime(&self) -> Option<SystemTime> {
+        fs::metadata(&self.path).and_then(|m| m.modified()).ok()
+    }
+}
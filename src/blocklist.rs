@@ -0,0 +1,288 @@
+use inotify::{Inotify, WatchDescriptor, WatchMask};
+use std::cell::RefCell;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use crate::net::{self, Net};
+
+/// Local lists of known bad addresses and networks, one per line with `#`
+/// or `;` comments (FireHOL netsets, Spamhaus DROP, ...).
+///
+/// Each list is read again whenever it is rewritten or replaced.
+pub struct Blocklists {
+    lists: RefCell<Vec<Blocklist>>,
+    index: RefCell<Index>,
+}
+
+struct Blocklist {
+    path: PathBuf,
+    dir: PathBuf,
+    file: OsString,
+    nets: Vec<Net>,
+    /// Watch on the parent directory, catches lists replaced by a rename.
+    dir_wd: Option<WatchDescriptor>,
+}
+
+/// All lists merged, `entries` index the listed networks and their lists.
+#[derive(Default)]
+struct Index {
+    v4: Trie,
+    v6: Trie,
+    entries: Vec<(Net, usize)>,
+}
+
+impl Blocklists {
+    pub fn load(paths: &[PathBuf]) -> Result<Self, io::Error> {
+        let lists = paths
+            .iter()
+            .map(|path| {
+                Ok(Blocklist {
+                    path: path.clone(),
+                    dir: path
+                        .parent()
+                        .unwrap_or_else(|| Path::new("/"))
+                        .to_path_buf(),
+                    file: path
+                        .file_name()
+                        .unwrap_or_else(|| OsStr::new(""))
+                        .to_os_string(),
+                    nets: read(path)?,
+                    dir_wd: None,
+                })
+            })
+            .collect::<Result<Vec<_>, io::Error>>()?;
+        let blocklists = Blocklists {
+            lists: RefCell::new(lists),
+            index: RefCell::new(Index::default()),
+        };
+        blocklists.reindex();
+
+        Ok(blocklists)
+    }
+
+    /// Watch the directories of all lists. The watches are added to what
+    /// is watched in the same directories already.
+    pub fn add_watches(&self, inotify: &mut Inotify) -> Result<(), io::Error> {
+        for list in self.lists.borrow_mut().iter_mut() {
+            list.dir_wd = Some(inotify.add_watch(
+                &list.dir,
+                WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::MASK_ADD,
+            )?);
+        }
+
+        Ok(())
+    }
+
+    /// Renew the watches and read all lists again after inotify events
+    /// were lost.
+    pub fn rescan(&self, inotify: &mut Inotify) -> Result<(), io::Error> {
+        self.add_watches(inotify)?;
+        self.reload(|_| true);
+
+        Ok(())
+    }
+
+    /// Read the list that is `name` in directory watch `wd` again, if any.
+    pub fn changed(&self, wd: &WatchDescriptor, name: Option<&OsStr>) {
+        self.reload(|list| list.dir_wd.as_ref() == Some(wd) && name == Some(list.file.as_os_str()));
+    }
+
+    /// The listed network containing `addr` and the path of its list.
+    pub fn lookup(&self, addr: IpAddr) -> Option<(Net, PathBuf)> {
+        let index = self.index.borrow();
+        let addr = net::normalize(addr);
+        let entry = match addr {
+            IpAddr::V4(a) => index.v4.lookup(u128::from(u32::from(a)) << 96, 32),
+            IpAddr::V6(a) => index.v6.lookup(u128::from(a), 128),
+        }?;
+        let (net, list) = index.entries[entry];
+
+        Some((net, self.lists.borrow()[list].path.clone()))
+    }
+
+    /// Read the lists selected by `filter` again. A list that cannot be
+    /// read is reported and kept as it was.
+    fn reload<F>(&self, filter: F)
+    where
+        F: Fn(&Blocklist) -> bool,
+    {
+        let mut reloaded = false;
+        for list in self.lists.borrow_mut().iter_mut().filter(|l| filter(l)) {
+            match read(&list.path) {
+                Ok(nets) => {
+                    list.nets = nets;
+                    reloaded = true;
+                }
+                Err(e) => eprintln!("watch: {}", e),
+            }
+        }
+        if reloaded {
+            self.reindex();
+        }
+    }
+
+    fn reindex(&self) {
+        let mut index = Index::default();
+        for (i, list) in self.lists.borrow().iter().enumerate() {
+            for net in &list.nets {
+                let entry = index.entries.len();
+                let inserted = match net.addr() {
+                    IpAddr::V4(a) => {
+                        index
+                            .v4
+                            .insert(u128::from(u32::from(a)) << 96, net.prefix_len(), entry)
+                    }
+                    IpAddr::V6(a) => index.v6.insert(u128::from(a), net.prefix_len(), entry),
+                };
+                if inserted {
+                    index.entries.push((*net, i));
+                }
+            }
+        }
+        *self.index.borrow_mut() = index;
+    }
+}
+
+/// Networks listed in the file at `path`, lines that are no address or
+/// network are skipped.
+fn read(path: &Path) -> Result<Vec<Net>, io::Error> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+
+    let mut invalid = 0;
+    let nets = content
+        .lines()
+        .map(|line| line.split(['#', ';']).next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| {
+            let net = line.parse::<Net>().ok();
+            if net.is_none() {
+                invalid += 1;
+            }
+            net
+        })
+        .collect();
+    if invalid > 0 {
+        eprintln!(
+            "watch: {}: ignoring {} invalid line(s)",
+            path.display(),
+            invalid
+        );
+    }
+
+    Ok(nets)
+}
+
+/// Binary trie over the bits of left-aligned addresses.
+struct Trie {
+    nodes: Vec<Node>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Node {
+    /// Node index per bit value, 0 for none, the root is no one's child.
+    children: [usize; 2],
+    /// Set on nodes that end a listed prefix.
+    entry: Option<usize>,
+}
+
+impl Default for Trie {
+    fn default() -> Self {
+        Trie {
+            nodes: vec![Node::default()],
+        }
+    }
+}
+
+impl Trie {
+    /// Add the prefix of `len` bits of `bits`. Returns false if it is
+    /// covered by a shorter prefix already.
+    fn insert(&mut self, bits: u128, len: u8, entry: usize) -> bool {
+        let mut node = 0;
+        for i in 0..len {
+            if self.nodes[node].entry.is_some() {
+                return false;
+            }
+            let bit = bit(bits, i);
+            node = match self.nodes[node].children[bit] {
+                0 => {
+                    self.nodes.push(Node::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children[bit] = child;
+                    child
+                }
+                child => child,
+            };
+        }
+        if self.nodes[node].entry.is_some() {
+            return false;
+        }
+        self.nodes[node].entry = Some(entry);
+
+        true
+    }
+
+    /// Entry of the shortest prefix covering the first `len` bits of
+    /// `bits`.
+    fn lookup(&self, bits: u128, len: u8) -> Option<usize> {
+        let mut node = 0;
+        for i in 0..len {
+            if let Some(entry) = self.nodes[node].entry {
+                return Some(entry);
+            }
+            node = match self.nodes[node].children[bit(bits, i)] {
+                0 => return None,
+                child => child,
+            };
+        }
+
+        self.nodes[node].entry
+    }
+}
+
+/// Bit `i` of `bits`, counting from the most significant one.
+fn bit(bits: u128, i: u8) -> usize {
+    ((bits >> (127 - i)) & 1) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn bits(addr: &str) -> u128 {
+        u128::from(u32::from(addr.parse::<Ipv4Addr>().unwrap())) << 96
+    }
+
+    #[test]
+    fn trie_lookup() {
+        let mut trie = Trie::default();
+        assert!(trie.insert(bits("192.0.2.0"), 24, 0));
+        assert!(trie.insert(bits("198.51.100.7"), 32, 1));
+        assert_eq!(trie.lookup(bits("192.0.2.200"), 32), Some(0));
+        assert_eq!(trie.lookup(bits("198.51.100.7"), 32), Some(1));
+        assert_eq!(trie.lookup(bits("198.51.100.8"), 32), None);
+        assert_eq!(trie.lookup(bits("192.0.3.1"), 32), None);
+    }
+
+    #[test]
+    fn trie_shorter_prefix_wins() {
+        let mut trie = Trie::default();
+        assert!(trie.insert(bits("10.1.0.0"), 16, 0));
+        assert!(trie.insert(bits("10.0.0.0"), 8, 1));
+        assert_eq!(trie.lookup(bits("10.1.2.3"), 32), Some(1));
+        assert_eq!(trie.lookup(bits("10.200.0.1"), 32), Some(1));
+        assert!(!trie.insert(bits("10.2.0.0"), 16, 2));
+        assert!(!trie.insert(bits("10.0.0.0"), 8, 3));
+    }
+
+    #[test]
+    fn trie_default_route() {
+        let mut trie = Trie::default();
+        assert!(trie.insert(0, 0, 0));
+        assert_eq!(trie.lookup(bits("203.0.113.9"), 32), Some(0));
+    }
+}
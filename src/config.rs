@@ -0,0 +1,330 @@
+use regex::Regex;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use toml::Spanned;
+
+use crate::allow::Allowlist;
+use crate::bans::BANS_PATH;
+use crate::blocklist::Blocklists;
+use crate::firewall::{Firewall, FirewallConfig};
+use crate::notifier::{Notifier, NotifierConfig};
+use crate::rule::{Auth, DEFAULT_BUILTIN};
+use crate::state::STATE_PATH;
+use crate::watched::Watched;
+use crate::{AuthFailure, FailureMap};
+
+pub const RULES_PATH: &str = "/etc/watch/rules.toml";
+pub const LOG_PATH: &str = "/var/log/auth.log";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Where read offsets are kept across restarts.
+    #[serde(default = "default_state_file")]
+    pub state_file: PathBuf,
+    /// Where bans are kept, see `watch bans`.
+    #[serde(default = "default_ban_file")]
+    pub ban_file: PathBuf,
+    /// Defaults to the `DEFAULT_BUILTIN` rules if empty and no `builtin`
+    /// rules are enabled.
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<AuthFailure>,
+    /// Built-in rules used next to the configured ones, by kind.
+    #[serde(default)]
+    pub builtin: Vec<Auth>,
+    /// Defaults to `LOG_PATH` with all rules if empty.
+    #[serde(default, rename = "file")]
+    pub files: Vec<LogFile>,
+    /// Defaults to a single stdout notifier if empty.
+    #[serde(default, rename = "notifier")]
+    pub notifiers: Vec<NotifierConfig>,
+    /// How rules with `ban` ban addresses.
+    pub firewall: Option<FirewallConfig>,
+    /// Failures never counted for any rule.
+    #[serde(default)]
+    pub allow: Allowlist,
+    /// Files of addresses and networks alerted on at their first failure.
+    #[serde(default)]
+    pub blocklists: Vec<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogFile {
+    pub path: PathBuf,
+    /// Names of the rules applied to this file, all rules if omitted.
+    pub rules: Option<Vec<String>>,
+}
+
+fn default_state_file() -> PathBuf {
+    PathBuf::from(STATE_PATH)
+}
+
+fn default_ban_file() -> PathBuf {
+    PathBuf::from(BANS_PATH)
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            state_file: default_state_file(),
+            ban_file: default_ban_file(),
+            rules: DEFAULT_BUILTIN
+                .iter()
+                .map(|kind| AuthFailure::builtin(*kind))
+                .collect(),
+            builtin: vec![],
+            files: vec![LogFile {
+                path: PathBuf::from(LOG_PATH),
+                rules: None,
+            }],
+            notifiers: vec![NotifierConfig::default()],
+            firewall: None,
+            allow: Allowlist::default(),
+            blocklists: vec![],
+        }
+    }
+}
+
+impl Config {
+    /// Read the rule file at `path`.
+    ///
+    /// Parse errors carry the line and column of the offending entry.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, io::Error> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        let mut config: Config = toml::from_str(&content).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })?;
+
+        for (i, rule) in config.rules.iter().enumerate() {
+            rule.validate().map_err(|e| {
+                let line = rule_line(&content, i).unwrap_or(1);
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: line {}: {}", path.display(), line, e),
+                )
+            })?;
+        }
+
+        for kind in &config.builtin {
+            config.rules.push(AuthFailure::builtin(*kind));
+        }
+        let default = Config::default();
+        if config.rules.is_empty() {
+            config.rules = default.rules;
+        }
+        if config.files.is_empty() {
+            config.files = default.files;
+        }
+        if config.notifiers.is_empty() {
+            config.notifiers = default.notifiers;
+        }
+
+        Ok(config)
+    }
+
+    /// Set up one `Watched` per configured file, each with its own copy of
+    /// its rules. Rules with `ban` share `firewall`, all rules share
+    /// `blocklists`.
+    pub fn watched(
+        &self,
+        firewall: Option<&Rc<Firewall>>,
+        blocklists: Option<&Rc<Blocklists>>,
+    ) -> Result<Vec<Watched>, io::Error> {
+        let notifiers = self
+            .notifiers
+            .iter()
+            .map(|n| Ok((n.name.as_str(), n.build()?)))
+            .collect::<Result<Vec<_>, io::Error>>()?;
+
+        let tags: Vec<&str> = self
+            .notifiers
+            .iter()
+            .filter_map(NotifierConfig::syslog_tag)
+            .collect();
+        let ignore = own_lines(&tags).map_err(|e| invalid(e.to_string()))?;
+
+        let mut watches = vec![];
+
+        for file in &self.files {
+            let rules = match &file.rules {
+                Some(names) => names
+                    .iter()
+                    .map(|name| {
+                        self.rules
+                            .iter()
+                            .find(|rule| &rule.name == name)
+                            .ok_or_else(|| {
+                                invalid(format!("{}: unknown rule `{}`", file.path.display(), name))
+                            })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+                None => self.rules.iter().collect(),
+            };
+
+            let mut failures = vec![];
+            let mut file_notifiers: Vec<Rc<dyn Notifier>> = vec![];
+            for rule in rules {
+                let resolve = |names: &Option<Vec<String>>| match names {
+                    Some(names) => names
+                        .iter()
+                        .map(|name| {
+                            notifiers
+                                .iter()
+                                .find(|(n, _)| n == name)
+                                .map(|(_, notifier)| notifier.clone())
+                                .ok_or_else(|| {
+                                    invalid(format!(
+                                        "rule `{}`: unknown notifier `{}`",
+                                        rule.name, name
+                                    ))
+                                })
+                        })
+                        .collect::<Result<Vec<_>, _>>(),
+                    None => Ok(notifiers.iter().map(|(_, n)| n.clone()).collect()),
+                };
+                let rule_notifiers = resolve(&rule.notifiers)?;
+                let escalations = rule
+                    .escalate
+                    .iter()
+                    .map(|step| match step.notifiers {
+                        Some(_) => resolve(&step.notifiers),
+                        None => Ok(rule_notifiers.clone()),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                let rule_firewall = match rule.ban {
+                    Some(_) => Some(firewall.cloned().ok_or_else(|| {
+                        invalid(format!(
+                            "rule `{}`: bans need a [firewall] section",
+                            rule.name
+                        ))
+                    })?),
+                    None => None,
+                };
+                for notifier in &rule_notifiers {
+                    if !file_notifiers.iter().any(|n| Rc::ptr_eq(n, notifier)) {
+                        file_notifiers.push(notifier.clone());
+                    }
+                }
+                let mut rule = rule.clone();
+                rule.allow.extend(&self.allow);
+                failures.push(FailureMap::new(
+                    rule,
+                    rule_notifiers,
+                    escalations,
+                    rule_firewall,
+                    blocklists.cloned(),
+                ));
+            }
+            watches.push(Watched::new(
+                &file.path,
+                failures,
+                file_notifiers,
+                ignore.clone(),
+            ));
+        }
+
+        Ok(watches)
+    }
+}
+
+/// Lines logged under one of `tags` by our own syslog notifiers, `None`
+/// without any. The tag has to be in the program field of the header, text
+/// further on the line is controlled by whoever caused it to be logged.
+fn own_lines(tags: &[&str]) -> Result<Option<Regex>, regex::Error> {
+    if tags.is_empty() {
+        return Ok(None);
+    }
+    let tags = tags
+        .iter()
+        .map(|tag| regex::escape(tag))
+        .collect::<Vec<_>>()
+        .join("|");
+
+    // `Mar  1 10:00:00 host tag[pid]: ` or with an RFC 3339 timestamp in
+    // traditional syslog files, `<pri>1 timestamp host tag pid alert ` as
+    // sent in RFC 5424 ones
+    Regex::new(&format!(
+        r"^(?:\S+\s+\d+\s+[\d:]+|\d{{4}}-\d\d-\d\dT\S+)\s+\S+\s+(?:{0})(?:\[\d+\])?:\s|^(?:<\d+>)?1\s\S+\s\S+\s(?:{0})\s\S+\salert\s",
+        tags
+    ))
+    .map(Some)
+}
+
+/// Line of the `i`th `[[rule]]` entry in the rule file `content`.
+fn rule_line(content: &str, i: usize) -> Option<usize> {
+    #[derive(Deserialize)]
+    struct Rules {
+        #[serde(default)]
+        rule: Vec<Spanned<toml::Table>>,
+    }
+
+    let rules: Rules = toml::from_str(content).ok()?;
+    let start = rules.rule.get(i)?.span().start;
+
+    Some(content[..start].matches('\n').count() + 1)
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = r#"
+# kern.log and nginx need no kind
+[[rule]]
+name = "kern"
+pattern = 'Out of memory'
+notify = "out of memory"
+
+[[rule]]
+name = "nginx"
+pattern = 'GET (?P<path>\S+) 401'
+notify = "nginx auth failures"
+key = ["rhost"]
+"#;
+
+    #[test]
+    fn rules_without_kind() {
+        let config: Config = toml::from_str(RULES).unwrap();
+        assert!(config.rules.iter().all(|rule| rule.kind.is_none()));
+        assert!(config.rules[0].validate().is_ok());
+        assert!(config.rules[1].validate().is_err());
+    }
+
+    #[test]
+    fn rule_lines() {
+        assert_eq!(rule_line(RULES, 0), Some(3));
+        assert_eq!(rule_line(RULES, 1), Some(8));
+        assert_eq!(rule_line(RULES, 2), None);
+    }
+
+    #[test]
+    fn own_lines_need_tag_in_header() {
+        let re = own_lines(&["watch"]).unwrap().unwrap();
+        assert!(re.is_match("Mar  1 10:00:00 host watch[42]: sudo bashing detected\n"));
+        assert!(
+            re.is_match("2020-03-01T10:00:00.123456+01:00 host watch[42]: sudo bashing detected\n")
+        );
+        assert!(re.is_match(
+            "<36>1 2020-03-01T10:00:00.123Z host watch 42 alert [alert@32473 rule=\"sudo\"] sudo\n"
+        ));
+        assert!(!re.is_match(
+            "Mar  1 10:00:00 host sshd[42]: Invalid user watch: from 192.0.2.1 port 4242\n"
+        ));
+        assert!(!re.is_match(
+            "Mar  1 10:00:00 host sshd[42]: Invalid user x watch 1 alert from 192.0.2.1 port 4242\n"
+        ));
+    }
+}
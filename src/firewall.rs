@@ -0,0 +1,331 @@
+use chrono::{SubsecRound, Utc};
+use serde::Deserialize;
+use std::cell::{Cell, RefCell, RefMut};
+use std::io;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::bans::{Ban, BanStore};
+use crate::net::Net;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum FirewallConfig {
+    Nftables(NftablesConfig),
+    Iptables(IptablesConfig),
+}
+
+/// Banned addresses are added to existing sets of type `ipv4_addr` and
+/// `ipv6_addr`, the ruleset decides what happens to them. Rules grouping
+/// addresses by prefix need sets with `flags interval`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NftablesConfig {
+    #[serde(default = "default_family")]
+    pub family: String,
+    #[serde(default = "default_table")]
+    pub table: String,
+    #[serde(default = "default_set")]
+    pub set: String,
+    #[serde(default = "default_set6")]
+    pub set6: String,
+    /// Only log the commands instead of running them.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Banned addresses get a DROP rule inserted into `chain`, by iptables or
+/// ip6tables.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IptablesConfig {
+    #[serde(default = "default_chain")]
+    pub chain: String,
+    /// Only log the commands instead of running them.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+fn default_family() -> String {
+    "inet".to_string()
+}
+
+fn default_table() -> String {
+    "filter".to_string()
+}
+
+fn default_set() -> String {
+    "watch".to_string()
+}
+
+fn default_set6() -> String {
+    "watch6".to_string()
+}
+
+fn default_chain() -> String {
+    "INPUT".to_string()
+}
+
+/// Delay before expired bans that could not be lifted are tried again.
+const UNBAN_RETRY: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy)]
+enum Op {
+    Add,
+    Check,
+    Delete,
+}
+
+/// Bans addresses and lifts the bans once they expire.
+pub struct Firewall {
+    config: FirewallConfig,
+    store: RefCell<BanStore>,
+    /// Set after lifting an expired ban failed, no new attempt before.
+    retry: Cell<Option<Instant>>,
+}
+
+impl Firewall {
+    pub fn new(config: &FirewallConfig, store: BanStore) -> Self {
+        Firewall {
+            config: config.clone(),
+            store: RefCell::new(store),
+            retry: Cell::new(None),
+        }
+    }
+
+    /// Apply the active bans of the store again, they are gone after a
+    /// reboot. Expired ones are lifted by the next `expire`.
+    pub fn restore(&self) {
+        let now = Utc::now();
+        let active: Vec<Net> = self
+            .store()
+            .bans
+            .iter()
+            .filter(|b| b.expires > now)
+            .map(|b| b.addr)
+            .collect();
+
+        for addr in active {
+            if let Err(e) = self.apply(addr) {
+                eprintln!("watch: restoring ban of {} failed: {}", addr, e);
+            }
+        }
+    }
+
+    /// Ban `addr` for `duration`. Banning an address again only extends
+    /// the ban.
+    pub fn ban(
+        &self,
+        addr: Net,
+        duration: Duration,
+        rule: &str,
+        reason: &str,
+    ) -> Result<(), io::Error> {
+        let now = Utc::now().trunc_subsecs(0);
+        let expires = now
+            + chrono::Duration::from_std(duration)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        let mut store = self.store();
+        match store.bans.iter_mut().find(|b| b.addr == addr) {
+            Some(ban) => ban.expires = expires.max(ban.expires),
+            None => {
+                self.apply(addr)?;
+                eprintln!("watch: banned {} for {}s", addr, duration.as_secs());
+                store.bans.push(Ban {
+                    addr,
+                    rule: rule.to_string(),
+                    reason: reason.to_string(),
+                    start: now,
+                    expires,
+                });
+            }
+        }
+        save(&mut store);
+
+        Ok(())
+    }
+
+    /// Lift the ban of `addr` before it expires, returns whether there was
+    /// one. The ban is only forgotten once it is lifted.
+    pub fn unban(&self, addr: Net) -> Result<bool, io::Error> {
+        let mut store = self.store();
+        if store.get(addr).is_none() {
+            return Ok(false);
+        }
+        self.lift(addr)?;
+        store.remove(addr);
+        store.save()?;
+
+        Ok(true)
+    }
+
+    /// Time left until the next ban expires, or until lifting failed bans
+    /// is tried again.
+    pub fn timeout(&self) -> Option<Duration> {
+        let next = self.store().bans.iter().map(|b| b.expires).min()?;
+        let timeout = (next - Utc::now()).to_std().unwrap_or_default();
+
+        Some(match self.retry.get() {
+            Some(t) => timeout.max(t.saturating_duration_since(Instant::now())),
+            None => timeout,
+        })
+    }
+
+    /// Lift all expired bans. A ban that cannot be lifted is reported and
+    /// kept, it is tried again after `UNBAN_RETRY`.
+    pub fn expire(&self) {
+        if self.retry.get().is_some_and(|t| t > Instant::now()) {
+            return;
+        }
+        self.retry.set(None);
+
+        let now = Utc::now();
+        let mut store = self.store();
+        let expired: Vec<Net> = store
+            .bans
+            .iter()
+            .filter(|b| b.expires <= now)
+            .map(|b| b.addr)
+            .collect();
+        if expired.is_empty() {
+            return;
+        }
+
+        for addr in expired {
+            match self.lift(addr) {
+                Ok(()) => {
+                    eprintln!("watch: unbanned {}", addr);
+                    store.remove(addr);
+                }
+                Err(e) => {
+                    eprintln!(
+                        "watch: unbanning {} failed: {}, retrying in {}s",
+                        addr,
+                        e,
+                        UNBAN_RETRY.as_secs()
+                    );
+                    self.retry.set(Some(Instant::now() + UNBAN_RETRY));
+                }
+            }
+        }
+        save(&mut store);
+    }
+
+    /// The store with changes made by `watch bans` picked up.
+    fn store(&self) -> RefMut<'_, BanStore> {
+        let mut store = self.store.borrow_mut();
+        if let Err(e) = store.refresh() {
+            eprintln!("watch: {}", e);
+        }
+        store
+    }
+
+    /// Add the ban of `addr` unless it is in place already.
+    fn apply(&self, addr: Net) -> Result<(), io::Error> {
+        if !self.dry_run() && self.succeeds(&self.command(addr, Op::Check)) {
+            return Ok(());
+        }
+        self.run(&self.command(addr, Op::Add))
+    }
+
+    /// Delete the ban of `addr` if it is in place, it is gone after a
+    /// reboot.
+    fn lift(&self, addr: Net) -> Result<(), io::Error> {
+        if !self.dry_run() && !self.succeeds(&self.command(addr, Op::Check)) {
+            return Ok(());
+        }
+        self.run(&self.command(addr, Op::Delete))
+    }
+
+    /// Command line adding, checking or deleting the ban of `addr`.
+    fn command(&self, addr: Net, op: Op) -> Vec<String> {
+        match &self.config {
+            FirewallConfig::Nftables(c) => {
+                let set = if addr.is_ipv4() { &c.set } else { &c.set6 };
+                let op = match op {
+                    Op::Add => "add",
+                    Op::Check => "get",
+                    Op::Delete => "delete",
+                };
+                vec![
+                    "nft".to_string(),
+                    op.to_string(),
+                    "element".to_string(),
+                    c.family.clone(),
+                    c.table.clone(),
+                    set.clone(),
+                    format!("{{ {} }}", addr),
+                ]
+            }
+            FirewallConfig::Iptables(c) => {
+                let op = match op {
+                    Op::Add => "-I",
+                    Op::Check => "-C",
+                    Op::Delete => "-D",
+                };
+                vec![
+                    if addr.is_ipv4() {
+                        "iptables"
+                    } else {
+                        "ip6tables"
+                    }
+                    .to_string(),
+                    "-w".to_string(),
+                    op.to_string(),
+                    c.chain.clone(),
+                    "-s".to_string(),
+                    addr.to_string(),
+                    "-j".to_string(),
+                    "DROP".to_string(),
+                ]
+            }
+        }
+    }
+
+    fn dry_run(&self) -> bool {
+        match &self.config {
+            FirewallConfig::Nftables(c) => c.dry_run,
+            FirewallConfig::Iptables(c) => c.dry_run,
+        }
+    }
+
+    /// Run `command` quietly, does it exit successfully?
+    fn succeeds(&self, command: &[String]) -> bool {
+        Command::new(&command[0])
+            .args(&command[1..])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    }
+
+    fn run(&self, command: &[String]) -> Result<(), io::Error> {
+        if self.dry_run() {
+            eprintln!("watch: dry-run: {}", command.join(" "));
+            return Ok(());
+        }
+
+        let output = Command::new(&command[0])
+            .args(&command[1..])
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", command[0], e)))?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!(
+                "`{}` {}: {}",
+                command.join(" "),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )))
+        }
+    }
+}
+
+fn save(store: &mut BanStore) {
+    if let Err(e) = store.save() {
+        eprintln!("watch: {}: {}", store.path().display(), e);
+    }
+}
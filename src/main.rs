@@ -1,166 +1,874 @@
-use inotify::{EventMask, Inotify, WatchMask};
-use std::ffi::OsStr;
-use std::fs::{self, File};
-use std::io::{self, BufRead, BufReader, Seek, SeekFrom};
+use chrono::{DateTime, Local, SecondsFormat};
+use inotify::{EventMask, Inotify};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::env;
+use std::ffi::OsString;
+use std::io;
+use std::mem;
+use std::net::IpAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::Path;
-use std::time::{Duration, Instant};
+use std::process;
+use std::ptr;
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime};
+
+mod allow;
+mod bans;
+mod blocklist;
+mod config;
+mod firewall;
+mod net;
+mod notifier;
+mod rule;
+mod state;
+mod template;
+mod watched;
+
+use bans::BanStore;
+use blocklist::Blocklists;
+use config::Config;
+use firewall::Firewall;
+use net::Net;
+use notifier::{Alert, Notifier};
+use rule::{AuthFailure, Failure};
+use state::State;
+use watched::Watched;
 
 const TIME_LIMIT: u64 = 300;
 const RATE_LIMIT: usize = 3;
+/// Room for a few hundred events of maximum size per read.
+const EVENT_BUFFER_SIZE: usize = 64 * 1024;
+/// Upper bound of aggregation keys tracked per rule and file.
+const MAX_KEYS: usize = 4096;
+/// Least time between two writes of the state file.
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
 
-struct Watched<'a> {
-    path: &'a Path,
-    file: &'a OsStr,
-    dir: &'a Path,
-    pos: u64,
+/// Failure times, the most recent matching lines and last notification for
+/// a single aggregation key.
+#[derive(Debug, Default)]
+struct KeyState {
+    auth_failure_time: Vec<Instant>,
+    lines: VecDeque<(Instant, String)>,
+    notify_time: Option<Instant>,
+    /// Cooldown after `notify_time`, grows with every further alert.
+    cooldown: Duration,
+    /// Alerts since the key was last quiet, for escalation.
+    alerts: usize,
+    /// Failures seen during the cooldown after `notify_time`, reported once
+    /// it ends.
+    suppressed: usize,
+    last_suppressed: Option<(Instant, Failure)>,
 }
 
-impl<'a> Watched<'a> {
-    fn new<P: AsRef<Path> + ?Sized>(f: &'a P) -> Self {
-        Watched {
-            path: f.as_ref(),
-            file: f.as_ref().file_name().unwrap_or_else(|| OsStr::new("")),
-            dir: f.as_ref().parent().unwrap_or_else(|| Path::new("/")),
-            pos: 0,
+impl KeyState {
+    /// Count a failure, keeping at most `max_lines` lines.
+    fn add(&mut self, line: &str, max_lines: usize) {
+        let now = Instant::now();
+        self.auth_failure_time.push(now);
+        self.lines.push_back((now, line.to_string()));
+        while self.lines.len() > max_lines {
+            self.lines.pop_front();
         }
     }
 
-    fn set_pos(&mut self, n: u64) {
-        self.pos = n;
+    fn clean(&mut self, window: Duration) {
+        self.auth_failure_time.retain(|x| x.elapsed() <= window);
+        self.lines.retain(|(x, _)| x.elapsed() <= window);
     }
-}
 
-#[derive(Debug)]
-enum Auth {
-    Sudo,
-    System,
-}
+    fn in_cooldown(&self) -> bool {
+        self.notify_time
+            .is_some_and(|t| t.elapsed() < self.cooldown)
+    }
+
+    /// No alert during the cooldown and a whole window after it, further
+    /// alerts are no longer escalated.
+    fn is_quiet(&self, window: Duration) -> bool {
+        self.notify_time
+            .is_none_or(|t| t.elapsed() >= self.cooldown + window)
+    }
 
-#[derive(Debug)]
-struct AuthFailure<'a> {
-    kind: Auth,
-    message: &'a str,
-    notify: &'a str,
+    /// Neither counting failures, escalating nor waiting to report
+    /// suppressed failures.
+    fn is_idle(&self, window: Duration) -> bool {
+        self.auth_failure_time.iter().all(|x| x.elapsed() > window)
+            && self.is_quiet(window)
+            && self.suppressed == 0
+    }
+
+    fn last_seen(&self) -> Option<Instant> {
+        self.auth_failure_time.last().copied().max(self.notify_time)
+    }
 }
 
-#[derive(Debug)]
-struct FailureMap<'a> {
-    auth_failure: AuthFailure<'a>,
-    auth_failure_time: Vec<Instant>,
-    notify_time: Option<Instant>,
+struct FailureMap {
+    auth_failure: AuthFailure,
+    notifiers: Vec<Rc<dyn Notifier>>,
+    /// Notifiers of each of the rule's escalation steps.
+    escalations: Vec<Vec<Rc<dyn Notifier>>>,
+    /// Set if the rule bans addresses.
+    firewall: Option<Rc<Firewall>>,
+    blocklists: Option<Rc<Blocklists>>,
+    /// When blocklisted addresses were last alerted on, at most once per
+    /// window.
+    listed: HashMap<IpAddr, Instant>,
+    /// Failures dropped by the allowlist since the start.
+    ignored: u64,
+    keys: HashMap<String, KeyState>,
+    evict_time: Instant,
 }
 
-impl<'a> FailureMap<'a> {
-    fn new(failure: AuthFailure<'a>) -> Self {
+impl FailureMap {
+    fn new(
+        failure: AuthFailure,
+        notifiers: Vec<Rc<dyn Notifier>>,
+        escalations: Vec<Vec<Rc<dyn Notifier>>>,
+        firewall: Option<Rc<Firewall>>,
+        blocklists: Option<Rc<Blocklists>>,
+    ) -> Self {
         FailureMap {
             auth_failure: failure,
-            auth_failure_time: vec![],
-            notify_time: None,
+            notifiers,
+            escalations,
+            firewall,
+            blocklists,
+            listed: HashMap::new(),
+            ignored: 0,
+            keys: HashMap::new(),
+            evict_time: Instant::now(),
         }
     }
 
-    fn add(&mut self) {
-        self.auth_failure_time.push(Instant::now());
+    /// Where alerts of escalation `step` go.
+    fn notifiers(&self, step: Option<usize>) -> &[Rc<dyn Notifier>] {
+        step.map_or(&self.notifiers, |i| &self.escalations[i])
     }
 
-    fn clean(&mut self) {
-        self.auth_failure_time
-            .retain(|x| x.elapsed() <= Duration::from_secs(TIME_LIMIT));
+    /// Alert right away if the address of `failure` is blocklisted, no
+    /// threshold applies.
+    fn check_blocklists(&mut self, failure: &Failure, key: &str) {
+        let (addr, blocklists) = match (failure.addr, &self.blocklists) {
+            (Some(addr), Some(blocklists)) => (addr, blocklists),
+            _ => return,
+        };
+        let (net, list) = match blocklists.lookup(addr) {
+            Some(found) => found,
+            None => return,
+        };
+        let window = self.auth_failure.window();
+        if self.listed.get(&addr).is_some_and(|t| t.elapsed() < window) {
+            return;
+        }
+        self.listed.retain(|_, t| t.elapsed() < window);
+        self.listed.insert(addr, Instant::now());
+
+        let mut fields = failure.fields.clone();
+        fields.insert("blocklist".to_string(), list.display().to_string());
+        fields.insert("listed".to_string(), net.to_string());
+        let now = SystemTime::now();
+        let alert = Alert {
+            rule: self.auth_failure.name.clone(),
+            kind: self.auth_failure.kind,
+            message: format!("{} is blocklisted", addr),
+            threshold: false,
+            severity: None,
+            key: key.to_string(),
+            fields,
+            count: 1,
+            window: window.as_secs(),
+            first_seen: now,
+            last_seen: now,
+            lines: vec![failure.line.clone()],
+        };
+        notifier::send(&self.notifiers, &alert);
     }
-}
 
-fn notify(fm: &mut FailureMap) -> Result<(), io::Error> {
-    fm.clean();
-    fm.add();
-    if fm.auth_failure_time.len() >= RATE_LIMIT {
-        if let Some(t) = fm.notify_time {
-            if t.elapsed() >= Duration::from_secs(TIME_LIMIT) {
-                println!("{}", fm.auth_failure.notify);
-                fm.notify_time = fm.auth_failure_time.pop();
-                fm.auth_failure_time = vec![];
-            }
-        } else {
-            println!("{}", fm.auth_failure.notify);
-            fm.notify_time = fm.auth_failure_time.pop();
-            fm.auth_failure_time = vec![];
+    /// Drop idle keys once per window, and the least recently seen keys
+    /// whenever there are more than `MAX_KEYS`.
+    fn evict(&mut self) {
+        let window = self.auth_failure.window();
+        if self.evict_time.elapsed() >= window {
+            self.keys.retain(|_, state| !state.is_idle(window));
+            self.evict_time = Instant::now();
+        }
+
+        while self.keys.len() >= MAX_KEYS {
+            let oldest = self
+                .keys
+                .iter()
+                .min_by_key(|(_, state)| state.last_seen())
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(key) => self.keys.remove(&key),
+                None => break,
+            };
+        }
+    }
+
+    /// Time left until the first cooldown with suppressed failures ends.
+    fn cooldown_timeout(&self) -> Option<Duration> {
+        self.keys
+            .values()
+            .filter(|state| state.suppressed > 0)
+            .filter_map(|state| {
+                state
+                    .notify_time
+                    .map(|t| state.cooldown.checked_sub(t.elapsed()).unwrap_or_default())
+            })
+            .min()
+    }
+
+    /// Report the failures suppressed during cooldowns that have ended, so
+    /// an ongoing attack does not look like it stopped.
+    fn report_suppressed(&mut self) {
+        let rule = &self.auth_failure;
+        let alerts: Vec<_> = self
+            .keys
+            .iter_mut()
+            .filter_map(|(key, state)| suppressed_alert(rule, key, state))
+            .collect();
+        for (alert, step) in alerts {
+            notifier::send(self.notifiers(step), &alert);
         }
     }
+}
+
+/// Summary of the failures suppressed during the cooldown of `state` once
+/// the cooldown has ended, with the escalation step of the last alert.
+fn suppressed_alert(
+    rule: &AuthFailure,
+    key: &str,
+    state: &mut KeyState,
+) -> Option<(Alert, Option<usize>)> {
+    if state.suppressed == 0 || state.in_cooldown() {
+        return None;
+    }
+    let step = rule.escalation(state.alerts.saturating_sub(1));
+    let since = state.notify_time.map_or_else(SystemTime::now, wall_time);
+    let last = state.last_suppressed.as_ref();
+    let alert = Alert {
+        rule: rule.name.clone(),
+        kind: rule.kind,
+        message: format!(
+            "{} further failures suppressed since {}",
+            state.suppressed,
+            DateTime::<Local>::from(since).format("%H:%M")
+        ),
+        threshold: false,
+        severity: step.map(|i| rule.escalate[i].severity),
+        key: key.to_string(),
+        fields: last.map(|(_, f)| f.fields.clone()).unwrap_or_default(),
+        count: state.suppressed,
+        window: rule.window.get(),
+        first_seen: since,
+        last_seen: last.map_or_else(SystemTime::now, |(t, _)| wall_time(*t)),
+        lines: state.lines.iter().map(|(_, line)| line.clone()).collect(),
+    };
+    state.suppressed = 0;
+    state.last_suppressed = None;
+
+    Some((alert, step))
+}
+
+fn notify(fm: &mut FailureMap, failure: &Failure) -> Result<(), io::Error> {
+    let window = fm.auth_failure.window();
+    let key = fm.auth_failure.key(failure);
+    fm.check_blocklists(failure, &key);
+    if !fm.keys.contains_key(&key) {
+        fm.evict();
+    }
+
+    // a cooldown that just ended is reported before any new alert
+    let rule = &fm.auth_failure;
+    let suppressed = fm
+        .keys
+        .get_mut(&key)
+        .and_then(|state| suppressed_alert(rule, &key, state));
+    if let Some((alert, step)) = suppressed {
+        notifier::send(fm.notifiers(step), &alert);
+    }
+
+    let state = fm.keys.entry(key.clone()).or_default();
+
+    state.clean(window);
+    state.add(&failure.line, rule.lines);
+    if state.in_cooldown() {
+        state.suppressed += 1;
+        state.last_suppressed = Some((Instant::now(), failure.clone()));
+        return Ok(());
+    }
+    if state.auth_failure_time.len() < rule.threshold.get() {
+        return Ok(());
+    }
+
+    if state.is_quiet(window) {
+        state.alerts = 0;
+    }
+    let step = rule.escalation(state.alerts);
+    let mut alert = Alert {
+        rule: rule.name.clone(),
+        kind: rule.kind,
+        message: String::new(),
+        threshold: true,
+        severity: step.map(|i| rule.escalate[i].severity),
+        key,
+        fields: failure.fields.clone(),
+        count: state.auth_failure_time.len(),
+        window: window.as_secs(),
+        first_seen: wall_time(state.auth_failure_time[0]),
+        last_seen: SystemTime::now(),
+        lines: state.lines.drain(..).map(|(_, line)| line).collect(),
+    };
+    alert.message = rule.notify.render(&alert, str::to_string);
+    state.notify_time = state.auth_failure_time.pop();
+    state.auth_failure_time = vec![];
+    state.cooldown = rule.cooldown(state.alerts);
+    state.alerts += 1;
+
+    notifier::send(fm.notifiers(step), &alert);
+    if let (Some(firewall), Some(ban)) = (&fm.firewall, fm.auth_failure.ban) {
+        ban_rhost(firewall, &alert, Duration::from_secs(ban.get()));
+    }
 
     Ok(())
 }
 
-fn main() -> Result<(), io::Error> {
-    let mut watch = Watched::new("/var/log/auth.log");
+/// Ban the `rhost` of `alert` if there is one, failures are reported but
+/// not fatal.
+fn ban_rhost(firewall: &Firewall, alert: &Alert, duration: Duration) {
+    let rhost = alert.fields.get("rhost").map_or("", String::as_str);
+    if rhost.is_empty() {
+        return;
+    }
+    let result = match rhost.parse::<Net>() {
+        Ok(addr) => firewall.ban(addr, duration, &alert.rule, &alert.message),
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
+    };
+    if let Err(e) = result {
+        eprintln!("watch: {}: ban failed: {}", alert.rule, e);
+    }
+}
 
-    let mut inotify = Inotify::init().expect("Failed to initialize inotify");
+/// Wall clock time of `instant`.
+fn wall_time(instant: Instant) -> SystemTime {
+    SystemTime::now() - instant.elapsed()
+}
 
-    inotify.add_watch(&watch.dir, WatchMask::CREATE | WatchMask::MOVED_FROM)?;
+fn main() {
+    let args: Vec<OsString> = env::args_os().skip(1).collect();
+    let result = match args.first() {
+        Some(arg) if arg == "bans" => bans(&args[1..]),
+        _ => run(args.first()),
+    };
+    if let Err(e) = result {
+        eprintln!("watch: {}", e);
+        process::exit(1);
+    }
+}
 
-    if watch.path.is_file() {
-        let meta = fs::metadata(&watch.path)?;
-        watch.set_pos(meta.len());
-        inotify.add_watch(&watch.path, WatchMask::MODIFY | WatchMask::MOVE_SELF)?;
+/// An explicitly given rule file has to exist, the default one is optional.
+fn load_config(path: Option<&OsString>) -> Result<Config, io::Error> {
+    match path {
+        Some(path) => Config::load(path),
+        None if Path::new(config::RULES_PATH).is_file() => Config::load(config::RULES_PATH),
+        None => Ok(Config::default()),
     }
+}
 
-    let sudo_failure = AuthFailure {
-        kind: Auth::Sudo,
-        message: "pam_unix(sudo:auth): authentication failure;",
-        notify: "sudo bashing detected",
+/// `watch bans list|remove <addr>|flush [rules.toml]`
+fn bans(args: &[OsString]) -> Result<(), io::Error> {
+    let usage = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "usage: watch bans list|remove <addr>|flush [rules.toml]",
+        )
     };
-    let systemauth_failure = AuthFailure {
-        kind: Auth::System,
-        message: "pam_unix(system-auth:auth): authentication failure;",
-        notify: "system-auth bashing detected",
+    let command = args.first().and_then(|a| a.to_str()).ok_or_else(usage)?;
+    let (addr, rest) = match command {
+        "remove" => {
+            let addr = args
+                .get(1)
+                .and_then(|a| a.to_str())
+                .ok_or_else(usage)?
+                .parse::<Net>()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            (Some(addr), &args[2..])
+        }
+        "list" | "flush" => (None, &args[1..]),
+        _ => return Err(usage()),
     };
+    if rest.len() > 1 {
+        return Err(usage());
+    }
 
-    let mut sudo_map = FailureMap::new(sudo_failure);
-    let mut system_map = FailureMap::new(systemauth_failure);
+    let config = load_config(rest.first())?;
+    let mut store = BanStore::load(&config.ban_file)?;
+    let addrs = match addr {
+        Some(addr) if store.get(addr).is_none() => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not banned", addr),
+            ))
+        }
+        Some(addr) => vec![addr],
+        None if command == "flush" => store.bans.iter().map(|b| b.addr).collect(),
+        None => {
+            for ban in &store.bans {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    ban.addr,
+                    ban.rule,
+                    ban.start.to_rfc3339_opts(SecondsFormat::Secs, true),
+                    ban.expires.to_rfc3339_opts(SecondsFormat::Secs, true),
+                    ban.reason
+                );
+            }
+            return Ok(());
+        }
+    };
 
-    let mut failures = vec![&mut sudo_map, &mut system_map];
+    // without a firewall there is nothing to lift, only the record
+    match &config.firewall {
+        Some(c) => {
+            let firewall = Firewall::new(c, store);
+            for addr in addrs {
+                firewall.unban(addr)?;
+            }
+        }
+        None => {
+            for addr in addrs {
+                store.remove(addr);
+            }
+            store.save()?;
+        }
+    }
 
-    let mut linebuffer = vec![];
+    Ok(())
+}
 
-    let mut buffer = [0_u8; 4096];
+fn run(path: Option<&OsString>) -> Result<(), io::Error> {
+    // before any notifier threads are started, they inherit the mask
+    let signals = termination_signals()?;
+    let config = load_config(path)?;
+
+    let firewall = match &config.firewall {
+        Some(c) => Some(Rc::new(Firewall::new(c, BanStore::load(&config.ban_file)?))),
+        None => None,
+    };
+    if let Some(firewall) = &firewall {
+        firewall.restore();
+    }
+    let blocklists = if config.blocklists.is_empty() {
+        None
+    } else {
+        Some(Rc::new(Blocklists::load(&config.blocklists)?))
+    };
+    let mut watches = config.watched(firewall.as_ref(), blocklists.as_ref())?;
+
+    let mut inotify = Inotify::init().expect("Failed to initialize inotify");
+
+    let saved = State::load(&config.state_file)?;
+    for watch in &mut watches {
+        let result = watch.add_watches(&mut inotify, saved.get(&watch.path));
+        report(watch, result);
+    }
+    // after the log files, their directory watches would replace these
+    if let Some(blocklists) = &blocklists {
+        blocklists.add_watches(&mut inotify)?;
+    }
+    let mut overflows = saved.overflows;
+    let ignored = saved.ignored.clone();
+    let mut state_file = StateFile::new(&config.state_file, saved);
+    state_file.save(state(&watches, overflows, &ignored), true);
+
+    let mut buffer = vec![0_u8; EVENT_BUFFER_SIZE];
 
     loop {
-        let events = inotify.read_events_blocking(&mut buffer)?;
-
-        for event in events {
-            if Some(OsStr::new(watch.file)) == event.name {
-                // directory events
-                if event.mask.contains(EventMask::CREATE) {
-                    // update watch
-                    inotify.add_watch(&watch.path, WatchMask::MODIFY | WatchMask::MOVE_SELF)?;
-                    watch.set_pos(0);
+        let timeout = watches
+            .iter()
+            .filter_map(Watched::line_timeout)
+            .chain(watches.iter().filter_map(Watched::rotated_timeout))
+            .chain(
+                watches
+                    .iter()
+                    .flat_map(|watch| &watch.failures)
+                    .filter_map(FailureMap::cooldown_timeout),
+            )
+            .chain(firewall.as_ref().and_then(|f| f.timeout()))
+            .chain(state_file.timeout())
+            .min();
+        let ready = poll(&inotify, signals, timeout)?;
+        if ready.signal {
+            state_file.save(state(&watches, overflows, &ignored), true);
+            return Ok(());
+        }
+        if ready.events {
+            let events = inotify.read_events(&mut buffer)?;
+
+            for event in events {
+                if event.mask.contains(EventMask::Q_OVERFLOW) {
+                    overflows += 1;
+                    eprintln!(
+                        "watch: inotify queue overflow ({} so far), rescanning",
+                        overflows
+                    );
+                    for watch in &mut watches {
+                        let result = watch.rescan(&mut inotify);
+                        report(watch, result);
+                    }
+                    if let Some(blocklists) = &blocklists {
+                        blocklists.rescan(&mut inotify)?;
+                    }
+                    continue;
+                }
+
+                if let Some(blocklists) = &blocklists {
+                    if event
+                        .mask
+                        .intersects(EventMask::CLOSE_WRITE | EventMask::MOVED_TO)
+                    {
+                        blocklists.changed(&event.wd, event.name);
+                    }
                 }
-            } else {
-                // file events
-                if !(event.mask.contains(EventMask::MOVE_SELF)
-                    | event.mask.contains(EventMask::IGNORED))
-                {
-                    let metadata = fs::metadata(&watch.path)?;
-                    let f = File::open(&watch.path)?;
-                    let mut reader = BufReader::new(f);
-                    reader.seek(SeekFrom::Start(watch.pos))?;
-
-                    loop {
-                        linebuffer.clear();
-                        let bytes_read = reader.read_until(b'\n', &mut linebuffer)?;
-                        if bytes_read == 0 {
-                            break;
-                        } else {
-                            for map in &mut failures {
-                                if String::from_utf8_lossy(&linebuffer[..])
-                                    .contains(map.auth_failure.message)
-                                {
-                                    notify(*map)?;
-                                }
-                            }
+
+                for watch in &mut watches {
+                    if watch.is_named(&event.wd, event.name) {
+                        // directory events
+                        if event.mask.contains(EventMask::MOVED_FROM) {
+                            let result = watch.rotated();
+                            report(watch, result);
+                        }
+                        if event.mask.contains(EventMask::CREATE) {
+                            // update watch
+                            let result = watch.created(&mut inotify);
+                            report(watch, result);
+                        }
+                    } else if watch.wd.as_ref() == Some(&event.wd) {
+                        // file events
+                        if !(event.mask.contains(EventMask::MOVE_SELF)
+                            | event.mask.contains(EventMask::IGNORED))
+                        {
+                            let result = watch.tail();
+                            report(watch, result);
+                        }
+                    } else if watch.pending.as_ref() == Some(&event.wd) {
+                        // the new file is written to, the rotated one is done
+                        if event.mask.contains(EventMask::MODIFY) {
+                            let result = watch.switch(&mut inotify);
+                            report(watch, result);
                         }
                     }
-                    watch.set_pos(metadata.len());
                 }
             }
         }
+
+        for watch in &mut watches {
+            let result = watch
+                .switch_stale(&mut inotify)
+                .and_then(|()| watch.flush_stale());
+            report(watch, result);
+            for map in &mut watch.failures {
+                map.report_suppressed();
+            }
+        }
+        if let Some(firewall) = &firewall {
+            firewall.expire();
+        }
+
+        state_file.save(state(&watches, overflows, &ignored), false);
+    }
+}
+
+/// Errors concerning a single file, like one created and removed again right
+/// away, are reported and the other files are watched on.
+fn report(watch: &Watched, result: Result<(), io::Error>) {
+    if let Err(e) = result {
+        eprintln!("watch: {}: {}", watch.path.display(), e);
+    }
+}
+
+/// Block SIGTERM and SIGINT, returns a signalfd they can be read from
+/// instead. The event loop ends on them, after saving the state.
+fn termination_signals() -> Result<RawFd, io::Error> {
+    unsafe {
+        let mut mask: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut mask);
+        libc::sigaddset(&mut mask, libc::SIGTERM);
+        libc::sigaddset(&mut mask, libc::SIGINT);
+        let ret = libc::pthread_sigmask(libc::SIG_BLOCK, &mask, ptr::null_mut());
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
+        }
+        match libc::signalfd(-1, &mask, libc::SFD_CLOEXEC) {
+            -1 => Err(io::Error::last_os_error()),
+            fd => Ok(fd),
+        }
+    }
+}
+
+/// What `poll` found ready.
+struct Ready {
+    events: bool,
+    signal: bool,
+}
+
+/// Wait until inotify events are ready to be read, a termination signal
+/// arrived on `signals`, or `timeout` passed.
+fn poll(inotify: &Inotify, signals: RawFd, timeout: Option<Duration>) -> Result<Ready, io::Error> {
+    let mut fds = [
+        libc::pollfd {
+            fd: inotify.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd: signals,
+            events: libc::POLLIN,
+            revents: 0,
+        },
+    ];
+    // round up, polling with a timeout of zero would spin until it is due
+    let timeout = timeout.map_or(-1, |t| {
+        (t.as_millis() + 1).min(libc::c_int::MAX as u128) as libc::c_int
+    });
+
+    let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+    if ret == -1 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+
+    Ok(Ready {
+        events: ret > 0 && fds[0].revents != 0,
+        signal: ret > 0 && fds[1].revents != 0,
+    })
+}
+
+/// The state file as last written. Changes are written at most once per
+/// `SAVE_INTERVAL`, writing it must not cause another write, it may live in
+/// a watched directory.
+struct StateFile<'a> {
+    path: &'a Path,
+    saved: State,
+    /// When the file was last written, or writing it failed.
+    save_time: Option<Instant>,
+    /// Set while changes are held back, or writing them failed.
+    pending: bool,
+}
+
+impl<'a> StateFile<'a> {
+    fn new(path: &'a Path, saved: State) -> Self {
+        StateFile {
+            path,
+            saved,
+            save_time: None,
+            pending: false,
+        }
+    }
+
+    /// Write `state` if it changed, right away if `force` is set.
+    fn save(&mut self, state: State, force: bool) {
+        if state == self.saved {
+            self.pending = false;
+            return;
+        }
+        let due = self.save_time.is_none_or(|t| t.elapsed() >= SAVE_INTERVAL);
+        if !force && !due {
+            self.pending = true;
+            return;
+        }
+        self.save_time = Some(Instant::now());
+        match state.save(self.path) {
+            Ok(()) => {
+                self.saved = state;
+                self.pending = false;
+            }
+            Err(e) => {
+                eprintln!("watch: {}: {}", self.path.display(), e);
+                self.pending = true;
+            }
+        }
+    }
+
+    /// Time left until changes held back are written.
+    fn timeout(&self) -> Option<Duration> {
+        if !self.pending {
+            return None;
+        }
+        self.save_time
+            .map(|t| SAVE_INTERVAL.checked_sub(t.elapsed()).unwrap_or_default())
+    }
+}
+
+/// Read offsets and counters to be saved. Ignored failures are added to the
+/// counts of previous runs in `ignored`.
+fn state(watches: &[Watched], overflows: u64, ignored: &BTreeMap<String, u64>) -> State {
+    let mut state = State {
+        overflows,
+        ignored: ignored.clone(),
+        files: watches.iter().filter_map(Watched::state).collect(),
+    };
+    for map in watches.iter().flat_map(|watch| &watch.failures) {
+        if map.ignored > 0 {
+            *state
+                .ignored
+                .entry(map.auth_failure.name.clone())
+                .or_default() += map.ignored;
+        }
+    }
+
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notifier::tests::Recorder;
+    use notifier::Severity;
+    use rule::{Auth, Escalation};
+    use std::num::{NonZeroU64, NonZeroUsize};
+
+    const LINE: &str = "sudo: pam_unix(sudo:auth): authentication failure; logname=bob uid=1000 euid=0 tty=/dev/pts/0 ruser=bob rhost=  user=bob";
+
+    /// The `sudo` rule alerting on every failure, with a one second window
+    /// and cooldown.
+    fn rule() -> AuthFailure {
+        let mut rule = AuthFailure::builtin(Auth::Sudo);
+        rule.threshold = NonZeroUsize::new(1).unwrap();
+        rule.window = NonZeroU64::new(1).unwrap();
+        rule
+    }
+
+    fn failure_map(rule: AuthFailure) -> (FailureMap, Rc<Recorder>) {
+        let recorder = Rc::new(Recorder::default());
+        let notifiers: Vec<Rc<dyn Notifier>> = vec![recorder.clone()];
+        let escalations = vec![notifiers.clone(); rule.escalate.len()];
+        let map = FailureMap::new(rule, notifiers, escalations, None, None);
+        (map, recorder)
+    }
+
+    fn failure(map: &FailureMap, user: &str) -> Failure {
+        map.auth_failure
+            .matches(&LINE.replace("bob", user))
+            .unwrap()
+    }
+
+    /// Pretend the last alert for `key` was raised `secs` earlier.
+    fn rewind(map: &mut FailureMap, key: &str, secs: u64) {
+        let state = map.keys.get_mut(key).unwrap();
+        state.notify_time = state
+            .notify_time
+            .and_then(|t| t.checked_sub(Duration::from_secs(secs)));
+    }
+
+    #[test]
+    fn cooldown_suppresses_and_reports() {
+        let (mut map, recorder) = failure_map(rule());
+        let bob = failure(&map, "bob");
+        let key = map.auth_failure.key(&bob);
+        notify(&mut map, &bob).unwrap();
+        notify(&mut map, &bob).unwrap();
+        notify(&mut map, &bob).unwrap();
+        assert_eq!(recorder.0.borrow().len(), 1);
+        assert!(recorder.0.borrow()[0].threshold);
+        assert_eq!(map.keys[&key].suppressed, 2);
+        assert!(map.cooldown_timeout().is_some());
+
+        // nothing to report while the cooldown lasts
+        map.report_suppressed();
+        assert_eq!(recorder.0.borrow().len(), 1);
+
+        rewind(&mut map, &key, 1);
+        assert_eq!(map.cooldown_timeout(), Some(Duration::from_secs(0)));
+        map.report_suppressed();
+        let alerts = recorder.0.borrow();
+        assert_eq!(alerts.len(), 2);
+        assert!(!alerts[1].threshold);
+        assert_eq!(alerts[1].count, 2);
+        assert!(alerts[1]
+            .message
+            .starts_with("2 further failures suppressed"));
+        assert_eq!(map.keys[&key].suppressed, 0);
+        assert!(map.cooldown_timeout().is_none());
+    }
+
+    #[test]
+    fn suppressed_reported_before_next_alert() {
+        let (mut map, recorder) = failure_map(rule());
+        let bob = failure(&map, "bob");
+        let key = map.auth_failure.key(&bob);
+        notify(&mut map, &bob).unwrap();
+        notify(&mut map, &bob).unwrap();
+        rewind(&mut map, &key, 1);
+        notify(&mut map, &bob).unwrap();
+
+        let alerts = recorder.0.borrow();
+        let thresholds: Vec<_> = alerts.iter().map(|a| a.threshold).collect();
+        assert_eq!(thresholds, vec![true, false, true]);
+        assert_eq!(alerts[1].count, 1);
+        assert!(
+            suppressed_alert(&map.auth_failure, &key, map.keys.get_mut(&key).unwrap()).is_none()
+        );
+    }
+
+    #[test]
+    fn escalation_reset_once_quiet() {
+        let mut rule = rule();
+        rule.escalate = vec![Escalation {
+            after: 1,
+            severity: Severity::Crit,
+            notifiers: None,
+        }];
+        let (mut map, recorder) = failure_map(rule);
+        let bob = failure(&map, "bob");
+        let key = map.auth_failure.key(&bob);
+        notify(&mut map, &bob).unwrap();
+        // right after the cooldown, within the window after it
+        rewind(&mut map, &key, 1);
+        assert!(!map.keys[&key].is_quiet(map.auth_failure.window()));
+        notify(&mut map, &bob).unwrap();
+        // a whole window after the cooldown
+        rewind(&mut map, &key, 2);
+        assert!(map.keys[&key].is_quiet(map.auth_failure.window()));
+        notify(&mut map, &bob).unwrap();
+
+        let alerts = recorder.0.borrow();
+        assert_eq!(alerts.len(), 3);
+        assert!(alerts[0].severity.is_none());
+        assert!(matches!(alerts[1].severity, Some(Severity::Crit)));
+        assert!(alerts[2].severity.is_none());
+        assert_eq!(map.keys[&key].alerts, 1);
+    }
+
+    #[test]
+    fn idle_keys_evicted() {
+        let (mut map, _) = failure_map(rule());
+        let bob = failure(&map, "bob");
+        let eve = failure(&map, "eve");
+        let window = map.auth_failure.window();
+        notify(&mut map, &bob).unwrap();
+        notify(&mut map, &eve).unwrap();
+        notify(&mut map, &eve).unwrap();
+        let bob = map.auth_failure.key(&bob);
+        let eve = map.auth_failure.key(&eve);
+        assert!(!map.keys[&bob].is_idle(window));
+
+        // both cooldowns and the windows after them are over, eve still has
+        // suppressed failures to report
+        rewind(&mut map, &bob, 2);
+        rewind(&mut map, &eve, 2);
+        assert!(map.keys[&bob].is_idle(window));
+        assert!(!map.keys[&eve].is_idle(window));
+
+        // idle keys are only dropped once per window
+        map.evict();
+        assert_eq!(map.keys.len(), 2);
+        map.evict_time = map.evict_time.checked_sub(window).unwrap();
+        map.evict();
+        assert!(!map.keys.contains_key(&bob));
+        assert!(map.keys.contains_key(&eve));
     }
 }
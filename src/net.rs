@@ -0,0 +1,179 @@
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// An address with prefix length, host bits cleared. A single address has
+/// the full length, 32 or 128.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Net {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Net {
+    /// `None` if `prefix_len` is longer than the address.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Self> {
+        let addr = match addr {
+            IpAddr::V4(a) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(prefix_len.min(32)));
+                IpAddr::V4(Ipv4Addr::from(u32::from(a) & mask.unwrap_or(0)))
+            }
+            IpAddr::V6(a) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(prefix_len.min(128)));
+                IpAddr::V6(Ipv6Addr::from(u128::from(a) & mask.unwrap_or(0)))
+            }
+        };
+        if prefix_len > max_len(addr) {
+            return None;
+        }
+
+        Some(Net { addr, prefix_len })
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn is_ipv4(&self) -> bool {
+        self.addr.is_ipv4()
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        addr.is_ipv4() == self.is_ipv4()
+            && Net::new(addr, self.prefix_len).is_some_and(|net| net.addr == self.addr)
+    }
+}
+
+impl From<IpAddr> for Net {
+    fn from(addr: IpAddr) -> Self {
+        Net {
+            addr,
+            prefix_len: max_len(addr),
+        }
+    }
+}
+
+/// Bits in `addr`.
+fn max_len(addr: IpAddr) -> u8 {
+    if addr.is_ipv4() {
+        32
+    } else {
+        128
+    }
+}
+
+/// `addr` with IPv4-mapped IPv6 addresses (`::ffff:192.0.2.1`) turned
+/// into plain IPv4 ones.
+pub fn normalize(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(a) => a.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+        IpAddr::V4(_) => addr,
+    }
+}
+
+impl FromStr for Net {
+    type Err = String;
+
+    /// `192.0.2.1`, `192.0.2.0/24`, `2001:db8::/64`, ...
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid address or network `{}`", s);
+        match s.split_once('/') {
+            Some((addr, len)) => {
+                let addr = addr.parse().map_err(|_| invalid())?;
+                let len = len.parse().map_err(|_| invalid())?;
+                Net::new(addr, len).ok_or_else(invalid)
+            }
+            None => s.parse::<IpAddr>().map(Net::from).map_err(|_| invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Net {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.prefix_len == max_len(self.addr) {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}/{}", self.addr, self.prefix_len)
+        }
+    }
+}
+
+impl Serialize for Net {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Net {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn net(s: &str) -> Net {
+        s.parse().unwrap()
+    }
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn new_clears_host_bits() {
+        assert_eq!(Net::new(addr("192.0.2.77"), 24), Some(net("192.0.2.0/24")));
+        assert_eq!(Net::new(addr("192.0.2.77"), 0), Some(net("0.0.0.0/0")));
+        assert_eq!(Net::new(addr("192.0.2.77"), 32), Some(net("192.0.2.77")));
+        assert_eq!(
+            Net::new(addr("2001:db8:1:2:3::1"), 64),
+            Some(net("2001:db8:1:2::/64"))
+        );
+        assert_eq!(Net::new(addr("2001:db8::1"), 0), Some(net("::/0")));
+        assert_eq!(Net::new(addr("2001:db8::1"), 128), Some(net("2001:db8::1")));
+    }
+
+    #[test]
+    fn new_rejects_long_prefixes() {
+        assert_eq!(Net::new(addr("192.0.2.1"), 33), None);
+        assert_eq!(Net::new(addr("2001:db8::1"), 129), None);
+        assert!("192.0.2.0/33".parse::<Net>().is_err());
+    }
+
+    #[test]
+    fn contains() {
+        assert!(net("192.0.2.0/24").contains(addr("192.0.2.255")));
+        assert!(!net("192.0.2.0/24").contains(addr("192.0.3.0")));
+        assert!(net("0.0.0.0/0").contains(addr("203.0.113.9")));
+        assert!(!net("0.0.0.0/0").contains(addr("2001:db8::1")));
+        assert!(net("::/0").contains(addr("2001:db8::1")));
+        assert!(!net("::/0").contains(addr("192.0.2.1")));
+        assert!(net("192.0.2.1").contains(addr("192.0.2.1")));
+        assert!(!net("192.0.2.1").contains(addr("192.0.2.2")));
+        assert!(net("2001:db8::1").contains(addr("2001:db8::1")));
+        assert!(!net("2001:db8::1").contains(addr("2001:db8::2")));
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(net("192.0.2.1").to_string(), "192.0.2.1");
+        assert_eq!(net("192.0.2.1/24").to_string(), "192.0.2.0/24");
+        assert_eq!(normalize(addr("::ffff:192.0.2.1")).to_string(), "192.0.2.1");
+    }
+}
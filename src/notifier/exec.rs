@@ -0,0 +1,202 @@
+use serde::Deserialize;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{rfc3339, Alert, Notifier};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExecConfig {
+    pub command: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Seconds until a still running command gets killed.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// Alerts arriving while this many commands are running are dropped.
+    #[serde(default = "default_max_running")]
+    pub max_running: usize,
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+fn default_max_running() -> usize {
+    4
+}
+
+/// Run a command for every alert.
+///
+/// The alert is passed in `WATCH_*` environment variables, one per captured
+/// field (`WATCH_USER`, `WATCH_RHOST`, ...) plus `WATCH_RULE`, `WATCH_KIND`,
+/// `WATCH_MESSAGE`, `WATCH_SEVERITY`, `WATCH_KEY`, `WATCH_COUNT`,
+/// `WATCH_WINDOW`, `WATCH_FIRST_SEEN` and `WATCH_LAST_SEEN`, and as JSON on
+/// stdin.
+pub struct Exec {
+    command: PathBuf,
+    args: Vec<String>,
+    timeout: Duration,
+    max_running: usize,
+    running: Arc<AtomicUsize>,
+}
+
+impl Exec {
+    pub fn new(config: &ExecConfig) -> Self {
+        Exec {
+            command: config.command.clone(),
+            args: config.args.clone(),
+            timeout: Duration::from_secs(config.timeout),
+            max_running: config.max_running,
+            running: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn env(alert: &Alert) -> Vec<(String, String)> {
+        let mut env: Vec<(String, String)> = alert
+            .fields
+            .iter()
+            .map(|(name, value)| (format!("WATCH_{}", name.to_uppercase()), value.clone()))
+            .collect();
+        env.extend(vec![
+            ("WATCH_RULE".to_string(), alert.rule.clone()),
+            (
+                "WATCH_KIND".to_string(),
+                alert.kind.map(|k| k.to_string()).unwrap_or_default(),
+            ),
+            ("WATCH_MESSAGE".to_string(), alert.message.clone()),
+            (
+                "WATCH_SEVERITY".to_string(),
+                alert.severity.map(|s| s.to_string()).unwrap_or_default(),
+            ),
+            ("WATCH_KEY".to_string(), alert.key.clone()),
+            ("WATCH_COUNT".to_string(), alert.count.to_string()),
+            ("WATCH_WINDOW".to_string(), alert.window.to_string()),
+            ("WATCH_FIRST_SEEN".to_string(), rfc3339(alert.first_seen)),
+            ("WATCH_LAST_SEEN".to_string(), rfc3339(alert.last_seen)),
+        ]);
+
+        env
+    }
+}
+
+impl Notifier for Exec {
+    fn notify(&self, alert: &Alert) -> Result<(), io::Error> {
+        if self.running.load(Ordering::SeqCst) >= self.max_running {
+            return Err(io::Error::other(format!(
+                "{}: {} commands still running, alert dropped",
+                self.command.display(),
+                self.max_running
+            )));
+        }
+
+        let json = serde_json::to_vec(alert)?;
+        let child = Command::new(&self.command)
+            .args(&self.args)
+            .envs(Exec::env(alert))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", self.command.display(), e)))?;
+
+        self.running.fetch_add(1, Ordering::SeqCst);
+        let running = self.running.clone();
+        let command = self.command.clone();
+        let rule = alert.rule.clone();
+        let timeout = self.timeout;
+        thread::spawn(move || {
+            match wait(child, json, timeout) {
+                Ok(Some(status)) => {
+                    eprintln!("watch: {}: {}: {}", rule, command.display(), status)
+                }
+                Ok(None) => eprintln!(
+                    "watch: {}: {}: killed after {}s",
+                    rule,
+                    command.display(),
+                    timeout.as_secs()
+                ),
+                Err(e) => eprintln!("watch: {}: {}: {}", rule, command.display(), e),
+            }
+            running.fetch_sub(1, Ordering::SeqCst);
+        });
+
+        Ok(())
+    }
+}
+
+/// Feed `input` to `child` and wait for it to exit, `None` if it had to be
+/// killed after `timeout`.
+///
+/// The input is written from its own thread, a command that does not read
+/// it must not keep the timeout from killing it.
+fn wait(
+    mut child: Child,
+    input: Vec<u8>,
+    timeout: Duration,
+) -> Result<Option<ExitStatus>, io::Error> {
+    let deadline = Instant::now() + timeout;
+    if let Some(mut stdin) = child.stdin.take() {
+        // a command not reading its input is fine
+        thread::spawn(move || stdin.write_all(&input));
+    }
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notifier::tests::alert;
+
+    #[test]
+    fn killed_after_timeout() {
+        let child = Command::new("sleep")
+            .arg("10")
+            .stdin(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let start = Instant::now();
+        // more than fits into the pipe, never read
+        let status = wait(child, vec![b'x'; 1 << 20], Duration::from_secs(1)).unwrap();
+        assert!(status.is_none());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let child = Command::new("true").stdin(Stdio::piped()).spawn().unwrap();
+        let status = wait(child, vec![], Duration::from_secs(5)).unwrap();
+        assert!(status.unwrap().success());
+    }
+
+    #[test]
+    fn max_running() {
+        let exec = Exec::new(&ExecConfig {
+            command: PathBuf::from("sleep"),
+            args: vec!["0.2".to_string()],
+            timeout: 5,
+            max_running: 1,
+        });
+        exec.notify(&alert()).unwrap();
+        assert!(exec.notify(&alert()).is_err());
+
+        let start = Instant::now();
+        while exec.running.load(Ordering::SeqCst) > 0 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(50));
+        }
+        exec.notify(&alert()).unwrap();
+    }
+}
@@ -0,0 +1,58 @@
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use super::{hostname, rfc3339, Alert, Notifier};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JsonConfig {
+    /// File to append to, stdout if omitted.
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    timestamp: String,
+    hostname: &'a str,
+    #[serde(flatten)]
+    alert: &'a Alert,
+}
+
+/// Write each alert as a single line JSON object.
+pub struct Json {
+    path: Option<PathBuf>,
+    hostname: String,
+}
+
+impl Json {
+    pub fn new(config: &JsonConfig) -> Self {
+        Json {
+            path: config.path.clone(),
+            hostname: hostname(),
+        }
+    }
+}
+
+impl Notifier for Json {
+    fn notify(&self, alert: &Alert) -> Result<(), io::Error> {
+        let mut line = serde_json::to_vec(&Line {
+            timestamp: rfc3339(SystemTime::now()),
+            hostname: &self.hostname,
+            alert,
+        })?;
+        line.push(b'\n');
+
+        match &self.path {
+            // reopened for each alert, the file may have been rotated
+            Some(path) => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?
+                .write_all(&line),
+            None => io::stdout().write_all(&line),
+        }
+    }
+}
@@ -0,0 +1,340 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::time::SystemTime;
+
+use crate::rule::Auth;
+use crate::template::Template;
+
+mod exec;
+mod json;
+mod smtp;
+mod stdout;
+mod syslog;
+mod webhook;
+
+pub use exec::Exec;
+pub use json::Json;
+pub use smtp::Smtp;
+pub use stdout::Stdout;
+pub use syslog::Syslog;
+pub use webhook::Webhook;
+
+/// Syslog severities, also used to escalate alerts.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Emerg = 0,
+    Alert = 1,
+    Crit = 2,
+    Err = 3,
+    Warning = 4,
+    Notice = 5,
+    Info = 6,
+    Debug = 7,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Severity::Emerg => "emerg",
+            Severity::Alert => "alert",
+            Severity::Crit => "crit",
+            Severity::Err => "err",
+            Severity::Warning => "warning",
+            Severity::Notice => "notice",
+            Severity::Info => "info",
+            Severity::Debug => "debug",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Everything known about an alert when it is raised.
+#[derive(Clone, Debug, Serialize)]
+pub struct Alert {
+    /// Name of the rule that fired.
+    pub rule: String,
+    /// Kind of the rule if it has one, `None` for alerts about a file.
+    pub kind: Option<Auth>,
+    /// The rule's notify text, filled in.
+    pub message: String,
+    /// Raised because a rule's threshold was reached. Only these alerts get
+    /// the notifier's template, others keep their message.
+    #[serde(skip)]
+    pub threshold: bool,
+    /// Set once repeated alerts for the same key were escalated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub severity: Option<Severity>,
+    /// Aggregation key the failures were counted under.
+    pub key: String,
+    /// Captured fields of the last matching line.
+    pub fields: BTreeMap<String, String>,
+    /// Number of failures within the window.
+    pub count: usize,
+    /// Length of the window in seconds.
+    pub window: u64,
+    #[serde(serialize_with = "serialize_time")]
+    pub first_seen: SystemTime,
+    #[serde(serialize_with = "serialize_time")]
+    pub last_seen: SystemTime,
+    /// Raw log lines that led to the alert.
+    pub lines: Vec<String>,
+}
+
+impl fmt::Display for Alert {
+    /// The notify text followed by all non-empty fields as `name=value`,
+    /// escalated alerts prefixed with their severity.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(severity) = self.severity {
+            write!(f, "[{}] ", severity)?;
+        }
+        write!(f, "{}", self.message)?;
+        for (name, value) in self.fields.iter().filter(|(_, v)| !v.is_empty()) {
+            write!(f, " {}={}", name, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// `time` as RFC 3339 timestamp in UTC.
+pub fn rfc3339(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn serialize_time<S>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&rfc3339(*time))
+}
+
+/// Name of this host, empty if it cannot be determined.
+pub fn hostname() -> String {
+    let mut buf = [0_u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+pub trait Notifier {
+    fn notify(&self, alert: &Alert) -> Result<(), io::Error>;
+}
+
+/// A notifier with its own message template.
+struct Templated {
+    template: Template,
+    notifier: Rc<dyn Notifier>,
+}
+
+impl Notifier for Templated {
+    fn notify(&self, alert: &Alert) -> Result<(), io::Error> {
+        if !alert.threshold {
+            return self.notifier.notify(alert);
+        }
+        let mut alert = alert.clone();
+        alert.message = self.template.render(&alert, str::to_string);
+        self.notifier.notify(&alert)
+    }
+}
+
+/// Hand `alert` to each of `notifiers`. A failing notifier is reported and
+/// does not keep the others from being notified.
+pub fn send(notifiers: &[Rc<dyn Notifier>], alert: &Alert) {
+    for notifier in notifiers {
+        if let Err(e) = notifier.notify(alert) {
+            eprintln!("watch: {}: notification failed: {}", alert.rule, e);
+        }
+    }
+}
+
+/// Hand `item` to the delivery thread of a notifier without waiting,
+/// `what` names the queue in errors.
+fn enqueue<T>(queue: &SyncSender<T>, what: &str, item: T) -> Result<(), io::Error> {
+    match queue.try_send(item) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full(_)) => Err(io::Error::other(format!(
+            "{} queue full, alert dropped",
+            what
+        ))),
+        Err(TrySendError::Disconnected(_)) => Err(io::Error::other(format!(
+            "{} delivery stopped, alert dropped",
+            what
+        ))),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NotifierConfig {
+    pub name: String,
+    /// Alert message for this notifier, the rule's notify text if omitted.
+    pub template: Option<Template>,
+    #[serde(flatten)]
+    pub kind: NotifierKind,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum NotifierKind {
+    Stdout(stdout::StdoutConfig),
+    Exec(exec::ExecConfig),
+    Webhook(webhook::WebhookConfig),
+    Smtp(smtp::SmtpConfig),
+    Syslog(syslog::SyslogConfig),
+    Json(json::JsonConfig),
+}
+
+impl NotifierConfig {
+    pub fn build(&self) -> Result<Rc<dyn Notifier>, io::Error> {
+        let notifier: Rc<dyn Notifier> = match &self.kind {
+            NotifierKind::Stdout(_) => Rc::new(Stdout),
+            NotifierKind::Exec(c) => Rc::new(Exec::new(c)),
+            NotifierKind::Webhook(c) => Rc::new(Webhook::new(c)),
+            NotifierKind::Smtp(c) => Rc::new(Smtp::new(&self.name, c)?),
+            NotifierKind::Syslog(c) => Rc::new(Syslog::new(c)?),
+            NotifierKind::Json(c) => Rc::new(Json::new(c)),
+        };
+
+        Ok(match &self.template {
+            Some(template) => Rc::new(Templated {
+                template: template.clone(),
+                notifier,
+            }),
+            None => notifier,
+        })
+    }
+
+    /// Syslog tag the notifier logs under, if it writes to syslog.
+    pub fn syslog_tag(&self) -> Option<&str> {
+        match &self.kind {
+            NotifierKind::Syslog(c) => Some(&c.tag),
+            _ => None,
+        }
+    }
+}
+
+impl Default for NotifierConfig {
+    fn default() -> Self {
+        NotifierConfig {
+            name: "stdout".to_string(),
+            template: None,
+            kind: NotifierKind::Stdout(stdout::StdoutConfig {}),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// An alert as raised by the `sudo` rule.
+    pub fn alert() -> Alert {
+        let line = "sudo: pam_unix(sudo:auth): authentication failure; logname=bob uid=1000 euid=0 tty=/dev/pts/0 ruser=bob rhost=  user=bob";
+        Alert {
+            rule: "sudo".to_string(),
+            kind: Some(Auth::Sudo),
+            message: "sudo bashing detected".to_string(),
+            threshold: true,
+            severity: None,
+            key: "user=bob rhost=".to_string(),
+            fields: vec![("user".to_string(), "bob".to_string())]
+                .into_iter()
+                .collect(),
+            count: 3,
+            window: 300,
+            first_seen: SystemTime::UNIX_EPOCH,
+            last_seen: SystemTime::UNIX_EPOCH,
+            lines: vec![line.to_string(); 3],
+        }
+    }
+
+    /// Keeps every alert it is notified of.
+    #[derive(Default)]
+    pub struct Recorder(pub RefCell<Vec<Alert>>);
+
+    impl Notifier for Recorder {
+        fn notify(&self, alert: &Alert) -> Result<(), io::Error> {
+            self.0.borrow_mut().push(alert.clone());
+            Ok(())
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct Notifiers {
+        notifier: Vec<NotifierConfig>,
+    }
+
+    fn parse(toml: &str) -> Result<Vec<NotifierConfig>, toml::de::Error> {
+        toml::from_str::<Notifiers>(toml).map(|n| n.notifier)
+    }
+
+    #[test]
+    fn config_name_and_template() {
+        let notifiers = parse(
+            r#"
+            [[notifier]]
+            type = "stdout"
+            name = "out"
+
+            [[notifier]]
+            type = "webhook"
+            name = "hook"
+            template = "{rule}: {message}"
+            url = "http://localhost/"
+            timeout = 5
+            "#,
+        )
+        .unwrap();
+        assert_eq!(notifiers[0].name, "out");
+        assert!(notifiers[0].template.is_none());
+        assert!(matches!(notifiers[0].kind, NotifierKind::Stdout(_)));
+        assert_eq!(notifiers[1].name, "hook");
+        assert_eq!(
+            notifiers[1]
+                .template
+                .as_ref()
+                .unwrap()
+                .render(&alert(), str::to_string),
+            "sudo: sudo bashing detected"
+        );
+        match &notifiers[1].kind {
+            NotifierKind::Webhook(c) => assert_eq!(c.timeout, 5),
+            _ => panic!("not a webhook"),
+        }
+    }
+
+    #[test]
+    fn config_unknown_fields() {
+        assert!(parse("[[notifier]]\ntype = \"stdout\"\nname = \"out\"\nurl = \"x\"").is_err());
+        assert!(parse("[[notifier]]\ntype = \"pager\"\nname = \"out\"").is_err());
+        assert!(parse("[[notifier]]\ntype = \"stdout\"").is_err());
+    }
+
+    #[test]
+    fn template_only_threshold_alerts() {
+        let recorder = Rc::new(Recorder::default());
+        let templated = Templated {
+            template: Template::from("{rule}: {count} failures"),
+            notifier: recorder.clone(),
+        };
+        let mut truncated = alert();
+        truncated.message = "log truncated".to_string();
+        truncated.threshold = false;
+        templated.notify(&alert()).unwrap();
+        templated.notify(&truncated).unwrap();
+
+        let alerts = recorder.0.borrow();
+        assert_eq!(alerts[0].message, "sudo: 3 failures");
+        assert_eq!(alerts[1].message, "log truncated");
+    }
+}
@@ -0,0 +1,270 @@
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use serde::Deserialize;
+use std::fmt::{self, Write};
+use std::io;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{enqueue, rfc3339, Alert, Notifier};
+
+/// Alerts waiting for the next digest before new ones get dropped.
+const QUEUE_SIZE: usize = 256;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Tls {
+    None,
+    Starttls,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SmtpConfig {
+    pub host: String,
+    /// Defaults to 25 without and 587 with STARTTLS.
+    pub port: Option<u16>,
+    #[serde(default = "default_tls")]
+    pub tls: Tls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    /// Seconds to collect alerts into a single digest mail, 0 sends each
+    /// alert on its own.
+    #[serde(default = "default_digest")]
+    pub digest: u64,
+    /// Seconds per SMTP command.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_tls() -> Tls {
+    Tls::Starttls
+}
+
+fn default_digest() -> u64 {
+    300
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+/// Mail alerts, folding all alerts within the digest window into one
+/// message.
+pub struct Smtp {
+    queue: SyncSender<Alert>,
+}
+
+impl Smtp {
+    pub fn new(name: &str, config: &SmtpConfig) -> Result<Self, io::Error> {
+        let port = config.port.unwrap_or(match config.tls {
+            Tls::None => 25,
+            Tls::Starttls => 587,
+        });
+        let mut builder = match config.tls {
+            Tls::None => SmtpTransport::builder_dangerous(&config.host),
+            Tls::Starttls => {
+                SmtpTransport::starttls_relay(&config.host).map_err(|e| invalid(name, e))?
+            }
+        }
+        .port(port)
+        .timeout(Some(Duration::from_secs(config.timeout)));
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        let from: Mailbox = config.from.parse().map_err(|e| invalid(name, e))?;
+        let to = config
+            .to
+            .iter()
+            .map(|to| to.parse().map_err(|e| invalid(name, e)))
+            .collect::<Result<Vec<Mailbox>, _>>()?;
+        if to.is_empty() {
+            return Err(invalid(name, "no recipients"));
+        }
+
+        let mailer = Mailer {
+            transport: builder.build(),
+            from,
+            to,
+        };
+        let digest = Duration::from_secs(config.digest);
+        let (queue, pending) = mpsc::sync_channel(QUEUE_SIZE);
+        thread::spawn(move || mailer.run(digest, pending));
+
+        Ok(Smtp { queue })
+    }
+}
+
+fn invalid<E: fmt::Display>(name: &str, e: E) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("notifier `{}`: {}", name, e),
+    )
+}
+
+impl Notifier for Smtp {
+    fn notify(&self, alert: &Alert) -> Result<(), io::Error> {
+        enqueue(&self.queue, "mail", alert.clone())
+    }
+}
+
+struct Mailer {
+    transport: SmtpTransport,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+}
+
+impl Mailer {
+    /// Collect alerts for `digest` after the first one arrived, then send
+    /// them all in one message.
+    fn run(self, digest: Duration, pending: Receiver<Alert>) {
+        while let Ok(alert) = pending.recv() {
+            let deadline = Instant::now() + digest;
+            let mut alerts = vec![alert];
+            loop {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                match pending.recv_timeout(timeout) {
+                    Ok(alert) => alerts.push(alert),
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+
+            if let Err(e) = self.send(&alerts) {
+                eprintln!(
+                    "watch: mail to {}: {}, {} alerts dropped",
+                    self.to[0],
+                    e,
+                    alerts.len()
+                );
+            }
+        }
+    }
+
+    fn send(&self, alerts: &[Alert]) -> Result<(), Box<dyn std::error::Error>> {
+        let subject = match alerts {
+            [alert] => format!("watch: {}", alert),
+            _ => format!("watch: {} alerts", alerts.len()),
+        };
+
+        let mut message = Message::builder().from(self.from.clone()).subject(subject);
+        for to in &self.to {
+            message = message.to(to.clone());
+        }
+        let message = message.body(digest(alerts))?;
+
+        self.transport.send(&message)?;
+
+        Ok(())
+    }
+}
+
+/// One paragraph per alert: rule, key and count, then the log lines.
+fn digest(alerts: &[Alert]) -> String {
+    let mut body = String::new();
+    for alert in alerts {
+        let _ = writeln!(
+            body,
+            "[{}] {}: {} in {}s, {} - {}\n{}",
+            alert.rule,
+            alert.key,
+            alert.count,
+            alert.window,
+            rfc3339(alert.first_seen),
+            rfc3339(alert.last_seen),
+            alert,
+        );
+        for line in &alert.lines {
+            let _ = writeln!(body, "    {}", line);
+        }
+        body.push('\n');
+    }
+
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notifier::tests::alert;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    /// Take a single SMTP session, returns the message data.
+    fn serve(listener: &TcpListener) -> String {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let reply = |reader: &mut BufReader<TcpStream>, line: &str| {
+            write!(reader.get_mut(), "{}\r\n", line).unwrap();
+        };
+        reply(&mut reader, "220 localhost ESMTP");
+
+        let mut data = String::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 {
+                break;
+            }
+            let command = line.trim_end().to_ascii_uppercase();
+            if command.starts_with("EHLO") {
+                reply(&mut reader, "250 localhost");
+            } else if command == "DATA" {
+                reply(&mut reader, "354 go ahead");
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == ".\r\n" {
+                        break;
+                    }
+                    data.push_str(&line);
+                }
+                reply(&mut reader, "250 queued");
+            } else if command == "QUIT" {
+                reply(&mut reader, "221 bye");
+                break;
+            } else {
+                reply(&mut reader, "250 ok");
+            }
+        }
+
+        data
+    }
+
+    #[test]
+    fn sends_digest() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let smtp = Smtp::new(
+            "mail",
+            &SmtpConfig {
+                host: "127.0.0.1".to_string(),
+                port: Some(listener.local_addr().unwrap().port()),
+                tls: Tls::None,
+                username: None,
+                password: None,
+                from: "watch@example.com".to_string(),
+                to: vec!["root@example.com".to_string()],
+                digest: 1,
+                timeout: 5,
+            },
+        )
+        .unwrap();
+
+        let mut other = alert();
+        other.rule = "system-auth".to_string();
+        smtp.notify(&alert()).unwrap();
+        smtp.notify(&other).unwrap();
+
+        // quoted-printable, only `=` needs decoding here
+        let data = serve(&listener).replace("=\r\n", "").replace("=3D", "=");
+        assert!(data.contains("Subject: watch: 2 alerts\r\n"));
+        assert!(data.contains("To: root@example.com\r\n"));
+        assert!(data.contains("[sudo] user=bob rhost=: 3 in 300s"));
+        assert!(data.contains("[system-auth] user=bob rhost=: 3 in 300s"));
+        assert!(data.contains("    sudo: pam_unix(sudo:auth): authentication failure;"));
+    }
+}
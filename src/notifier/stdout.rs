@@ -0,0 +1,37 @@
+use serde::Deserialize;
+use std::io::{self, Write};
+
+use super::{rfc3339, Alert, Notifier};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StdoutConfig {}
+
+/// Print alerts to stdout, followed by the lines that led to them.
+pub struct Stdout;
+
+impl Notifier for Stdout {
+    fn notify(&self, alert: &Alert) -> Result<(), io::Error> {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        // alerts about a file itself have no window
+        if alert.window > 0 {
+            writeln!(
+                out,
+                "{} ({} in {}s, {} - {})",
+                alert,
+                alert.count,
+                alert.window,
+                rfc3339(alert.first_seen),
+                rfc3339(alert.last_seen)
+            )?;
+        } else {
+            writeln!(out, "{}", alert)?;
+        }
+        for line in &alert.lines {
+            writeln!(out, "    {}", line)?;
+        }
+
+        Ok(())
+    }
+}
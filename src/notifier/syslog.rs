@@ -0,0 +1,297 @@
+use serde::Deserialize;
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::process;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use super::{enqueue, hostname, Alert, Notifier, Severity};
+use chrono::{DateTime, SecondsFormat, Utc};
+
+pub const DEFAULT_TAG: &str = "watch";
+/// Enterprise number reserved for documentation, RFC 5612.
+const SD_ID: &str = "32473";
+/// Messages waiting to be sent before new ones get dropped.
+const QUEUE_SIZE: usize = 64;
+/// Timeout for connecting and for each write over TCP.
+const TCP_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Unix,
+    Udp,
+    Tcp,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Facility {
+    Kern = 0,
+    User = 1,
+    Mail = 2,
+    Daemon = 3,
+    Auth = 4,
+    Syslog = 5,
+    Authpriv = 10,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyslogConfig {
+    #[serde(default = "default_transport")]
+    pub transport: Transport,
+    /// Socket path for `unix`, `host:port` for `udp` and `tcp`.
+    #[serde(default = "default_address")]
+    pub address: String,
+    #[serde(default = "default_facility")]
+    pub facility: Facility,
+    /// Severity of alerts that were not escalated.
+    #[serde(default = "default_severity")]
+    pub severity: Severity,
+    /// APP-NAME of the messages. Watched lines logged under this tag are
+    /// ignored so alerts do not trigger further alerts.
+    #[serde(default = "default_tag")]
+    pub tag: String,
+}
+
+fn default_transport() -> Transport {
+    Transport::Unix
+}
+
+fn default_address() -> String {
+    "/dev/log".to_string()
+}
+
+fn default_facility() -> Facility {
+    Facility::Auth
+}
+
+fn default_severity() -> Severity {
+    Severity::Warning
+}
+
+fn default_tag() -> String {
+    DEFAULT_TAG.to_string()
+}
+
+enum Socket {
+    Unix(UnixDatagram),
+    /// Bound on first use for the address family of the destination, and
+    /// again after errors.
+    Udp(Option<(UdpSocket, SocketAddr)>),
+    /// Connected on first use and after errors.
+    Tcp(Option<TcpStream>),
+}
+
+/// Send alerts as RFC 5424 messages, with rule and captured fields as
+/// structured data.
+///
+/// Messages are sent from a separate thread so a stalled syslog daemon or
+/// server does not hold up reading the logs.
+pub struct Syslog {
+    queue: SyncSender<(String, String)>,
+    facility: Facility,
+    severity: Severity,
+    tag: String,
+    hostname: String,
+}
+
+impl Syslog {
+    pub fn new(config: &SyslogConfig) -> Result<Self, io::Error> {
+        let socket = match config.transport {
+            Transport::Unix => Socket::Unix(UnixDatagram::unbound()?),
+            Transport::Udp => Socket::Udp(None),
+            Transport::Tcp => Socket::Tcp(None),
+        };
+
+        let (queue, pending) = mpsc::sync_channel(QUEUE_SIZE);
+        let address = config.address.clone();
+        thread::spawn(move || deliver(socket, address, pending));
+
+        Ok(Syslog {
+            queue,
+            facility: config.facility,
+            severity: config.severity,
+            tag: config.tag.clone(),
+            hostname: hostname(),
+        })
+    }
+
+    fn format(&self, alert: &Alert) -> String {
+        let severity = alert.severity.unwrap_or(self.severity);
+        let pri = self.facility as u8 * 8 + severity as u8;
+        let timestamp =
+            DateTime::<Utc>::from(SystemTime::now()).to_rfc3339_opts(SecondsFormat::Millis, true);
+
+        let mut sd = format!(
+            "[alert@{} rule=\"{}\" key=\"{}\" count=\"{}\" window=\"{}\"]",
+            SD_ID,
+            sd_escape(&alert.rule),
+            sd_escape(&alert.key),
+            alert.count,
+            alert.window
+        );
+        let fields: Vec<_> = alert
+            .fields
+            .iter()
+            .filter(|(name, _)| is_sd_name(name))
+            .map(|(name, value)| format!(" {}=\"{}\"", name, sd_escape(value)))
+            .collect();
+        if !fields.is_empty() {
+            sd.push_str(&format!("[fields@{}{}]", SD_ID, fields.concat()));
+        }
+
+        format!(
+            "<{}>1 {} {} {} {} alert {} {}",
+            pri,
+            timestamp,
+            self.hostname,
+            self.tag,
+            process::id(),
+            sd,
+            alert
+        )
+    }
+}
+
+impl Notifier for Syslog {
+    fn notify(&self, alert: &Alert) -> Result<(), io::Error> {
+        enqueue(
+            &self.queue,
+            "syslog",
+            (alert.rule.clone(), self.format(alert)),
+        )
+    }
+}
+
+fn deliver(mut socket: Socket, address: String, pending: Receiver<(String, String)>) {
+    for (rule, message) in pending {
+        if let Err(e) = send(&mut socket, &address, &message) {
+            eprintln!("watch: {}: {}: {}, alert dropped", rule, address, e);
+        }
+    }
+}
+
+fn send(socket: &mut Socket, address: &str, message: &str) -> Result<(), io::Error> {
+    match socket {
+        Socket::Unix(socket) => socket
+            .send_to(message.as_bytes(), PathBuf::from(address))
+            .map(|_| ()),
+        Socket::Udp(bound) => {
+            if bound.is_none() {
+                *bound = Some(bind(address)?);
+            }
+            let result = bound
+                .as_ref()
+                .map_or(Ok(0), |(s, addr)| s.send_to(message.as_bytes(), addr));
+            if result.is_err() {
+                *bound = None;
+            }
+            result.map(|_| ())
+        }
+        Socket::Tcp(stream) => {
+            if stream.is_none() {
+                *stream = Some(connect(address)?);
+            }
+            // octet counting framing, RFC 6587
+            let framed = format!("{} {}", message.len(), message);
+            let result = stream
+                .as_mut()
+                .map_or(Ok(()), |s| s.write_all(framed.as_bytes()));
+            if result.is_err() {
+                *stream = None;
+            }
+            result
+        }
+    }
+}
+
+/// A socket to send to the first address of `address` from, bound to the
+/// unspecified address of the same family.
+fn bind(address: &str) -> Result<(UdpSocket, SocketAddr), io::Error> {
+    let addr = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address to send to"))?;
+    let local = match addr {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+
+    Ok((UdpSocket::bind(local)?, addr))
+}
+
+/// Connect to the first reachable address of `address`, with timeouts.
+fn connect(address: &str) -> Result<TcpStream, io::Error> {
+    let mut last = None;
+    for addr in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, TCP_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_write_timeout(Some(TCP_TIMEOUT))?;
+                return Ok(stream);
+            }
+            Err(e) => last = Some(e),
+        }
+    }
+
+    Err(last.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address to connect to")))
+}
+
+/// PARAM-VALUE escaping, RFC 5424 section 6.3.3.
+fn sd_escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace(']', "\\]")
+}
+
+/// Can `name` be used as PARAM-NAME?
+fn is_sd_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 32
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_graphic() && !matches!(b, b'=' | b']' | b'"'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notifier::tests::alert;
+
+    #[test]
+    fn udp_to_both_families() {
+        for local in &["127.0.0.1:0", "[::1]:0"] {
+            let server = UdpSocket::bind(local).unwrap();
+            server
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let syslog = Syslog::new(&SyslogConfig {
+                transport: Transport::Udp,
+                address: server.local_addr().unwrap().to_string(),
+                facility: Facility::Auth,
+                severity: Severity::Warning,
+                tag: DEFAULT_TAG.to_string(),
+            })
+            .unwrap();
+            syslog.notify(&alert()).unwrap();
+
+            let mut buf = [0; 4096];
+            let len = server.recv(&mut buf).unwrap();
+            let message = String::from_utf8_lossy(&buf[..len]);
+            assert!(message.starts_with("<36>1 "), "{}", message);
+            assert!(message.ends_with("sudo bashing detected user=bob"));
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+use std::time::Duration;
+use ureq::Agent;
+
+use super::{enqueue, Alert, Notifier};
+use crate::template::{json_escape, Template};
+
+/// Alerts waiting for delivery before new ones get dropped.
+const QUEUE_SIZE: usize = 64;
+/// Delay before the first retry, doubled for each further one.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// JSON body, the alert as JSON object if omitted.
+    pub body: Option<Template>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Seconds per request.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+/// POST alerts to a URL.
+///
+/// Requests are sent from a separate thread so a slow or unreachable
+/// endpoint does not hold up reading the logs.
+pub struct Webhook {
+    body: Option<Template>,
+    queue: SyncSender<(String, String)>,
+}
+
+impl Webhook {
+    pub fn new(config: &WebhookConfig) -> Self {
+        let (queue, pending) = mpsc::sync_channel(QUEUE_SIZE);
+        let agent: Agent = Agent::config_builder()
+            .timeout_global(Some(Duration::from_secs(config.timeout)))
+            .build()
+            .into();
+        let url = config.url.clone();
+        let headers = config.headers.clone();
+        let retries = config.retries;
+        thread::spawn(move || deliver(agent, url, headers, retries, pending));
+
+        Webhook {
+            body: config.body.clone(),
+            queue,
+        }
+    }
+}
+
+impl Notifier for Webhook {
+    fn notify(&self, alert: &Alert) -> Result<(), io::Error> {
+        let body = match &self.body {
+            Some(template) => template.render(alert, json_escape),
+            None => serde_json::to_string(alert)?,
+        };
+
+        enqueue(&self.queue, "webhook", (alert.rule.clone(), body))
+    }
+}
+
+fn deliver(
+    agent: Agent,
+    url: String,
+    headers: BTreeMap<String, String>,
+    retries: u32,
+    pending: Receiver<(String, String)>,
+) {
+    for (rule, body) in pending {
+        let mut delay = RETRY_DELAY;
+        for attempt in 0..=retries {
+            let mut request = agent.post(&url).content_type("application/json");
+            for (name, value) in &headers {
+                request = request.header(name, value);
+            }
+            match request.send(&body) {
+                Ok(_) => break,
+                Err(e) if attempt < retries => {
+                    eprintln!("watch: {}: {}: {}, retrying in {:?}", rule, url, e, delay);
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+                Err(e) => eprintln!("watch: {}: {}: {}, alert dropped", rule, url, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notifier::tests::alert;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    /// Answer a single request with `status`, returns its head and body.
+    fn serve(listener: &TcpListener, status: &str) -> (String, String) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut head = String::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line.trim_end().is_empty() {
+                break;
+            }
+            head.push_str(&line);
+        }
+        let len = head
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                if name.eq_ignore_ascii_case("content-length") {
+                    value.trim().parse().ok()
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(0);
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body).unwrap();
+        write!(
+            reader.get_mut(),
+            "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            status
+        )
+        .unwrap();
+
+        (head, String::from_utf8(body).unwrap())
+    }
+
+    fn webhook(listener: &TcpListener, body: Option<&str>, retries: u32) -> Webhook {
+        let mut headers = BTreeMap::new();
+        headers.insert("X-Token".to_string(), "secret".to_string());
+        Webhook::new(&WebhookConfig {
+            url: format!("http://{}/alert", listener.local_addr().unwrap()),
+            body: body.map(Template::from),
+            headers,
+            timeout: 5,
+            retries,
+        })
+    }
+
+    #[test]
+    fn posts_alert_as_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        webhook(&listener, None, 0).notify(&alert()).unwrap();
+
+        let (head, body) = serve(&listener, "200 OK");
+        assert!(head.starts_with("POST /alert HTTP/1.1"));
+        assert!(head.to_ascii_lowercase().contains("x-token: secret"));
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["rule"], "sudo");
+        assert_eq!(body["kind"], "sudo");
+        assert_eq!(body["count"], 3);
+        assert_eq!(body["fields"]["user"], "bob");
+        assert_eq!(body["first_seen"], "1970-01-01T00:00:00Z");
+    }
+
+    #[test]
+    fn retries_with_body_template() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        webhook(&listener, Some(r#"{"text": "{message} by {user}"}"#), 1)
+            .notify(&alert())
+            .unwrap();
+
+        let (_, first) = serve(&listener, "503 Service Unavailable");
+        assert_eq!(first, r#"{"text": "sudo bashing detected by bob"}"#);
+        let (_, second) = serve(&listener, "200 OK");
+        assert_eq!(second, r#"{"text": "sudo bashing detected by bob"}"#);
+    }
+}
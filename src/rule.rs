@@ -0,0 +1,433 @@
+use regex::Regex;
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::num::{NonZeroU32, NonZeroU64, NonZeroUsize};
+use std::time::Duration;
+
+use crate::allow::Allowlist;
+use crate::net::{self, Net};
+use crate::notifier::Severity;
+use crate::template::Template;
+use crate::{RATE_LIMIT, TIME_LIMIT};
+
+/// Fields pam_unix logs after "authentication failure;".
+const PAM_UNIX_FIELDS: &str = r"logname=(?P<logname>\S*) uid=(?P<uid>\d*) euid=(?P<euid>\d*) tty=(?P<tty>\S*) ruser=(?P<ruser>\S*) rhost=(?P<rhost>\S*)(?:\s+user=(?P<user>\S*))?";
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Auth {
+    Sudo,
+    System,
+    SshdFailedPassword,
+    SshdInvalidUser,
+    /// Clients giving up after failed attempts, `[preauth]`.
+    SshdPreauth,
+    SshdMaxAuthTries,
+}
+
+/// Built-in rules used if no rules are configured.
+pub const DEFAULT_BUILTIN: [Auth; 2] = [Auth::Sudo, Auth::System];
+
+impl fmt::Display for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Auth::Sudo => write!(f, "sudo"),
+            Auth::System => write!(f, "system"),
+            Auth::SshdFailedPassword => write!(f, "sshd-failed-password"),
+            Auth::SshdInvalidUser => write!(f, "sshd-invalid-user"),
+            Auth::SshdPreauth => write!(f, "sshd-preauth"),
+            Auth::SshdMaxAuthTries => write!(f, "sshd-max-auth-tries"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthFailure {
+    pub name: String,
+    /// Reported with alerts, always set for built-in rules.
+    pub kind: Option<Auth>,
+    /// Named capture groups end up as fields of the `Failure`.
+    #[serde(deserialize_with = "deserialize_regex")]
+    pub pattern: Regex,
+    /// Alert message, a `Template`.
+    pub notify: Template,
+    /// Capture groups whose values make up the aggregation key. Failures
+    /// are counted per distinct key, a rule without key counts them all
+    /// together.
+    #[serde(default)]
+    pub key: Vec<String>,
+    #[serde(default = "default_threshold")]
+    pub threshold: NonZeroUsize,
+    #[serde(default = "default_window")]
+    pub window: NonZeroU64,
+    /// Number of the most recent matching lines included in an alert.
+    #[serde(default = "default_lines")]
+    pub lines: usize,
+    /// Names of the notifiers alerts go to, all notifiers if omitted.
+    pub notifiers: Option<Vec<String>>,
+    /// Factor the cooldown after an alert grows by with every further
+    /// alert for the same key, 1 keeps it at `window`.
+    #[serde(default = "default_backoff")]
+    pub backoff: NonZeroU32,
+    /// Upper bound of the cooldown in seconds.
+    #[serde(default = "default_max_cooldown")]
+    pub max_cooldown: NonZeroU64,
+    #[serde(default)]
+    pub escalate: Vec<Escalation>,
+    /// Ban time in seconds for the captured `rhost` once the threshold is
+    /// reached, needs a `[firewall]` and `rhost` in the key.
+    pub ban: Option<NonZeroU64>,
+    /// Prefix lengths `rhost` addresses are grouped by, e.g. 24 and 64.
+    /// Failures are counted and banned per prefix, `rhost` is the prefix.
+    pub ipv4_prefix: Option<u8>,
+    pub ipv6_prefix: Option<u8>,
+    /// Failures never counted for this rule, in addition to the global
+    /// allowlist.
+    #[serde(default)]
+    pub allow: Allowlist,
+}
+
+/// Applies to alerts for a key that already had `after` alerts since it
+/// was last quiet for a whole window after its cooldown.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Escalation {
+    pub after: usize,
+    pub severity: Severity,
+    /// Names of the notifiers escalated alerts go to instead of the rule's.
+    pub notifiers: Option<Vec<String>>,
+}
+
+fn deserialize_regex<'de, D>(deserializer: D) -> Result<Regex, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern).map_err(de::Error::custom)
+}
+
+fn default_threshold() -> NonZeroUsize {
+    NonZeroUsize::new(RATE_LIMIT).unwrap()
+}
+
+fn default_window() -> NonZeroU64 {
+    NonZeroU64::new(TIME_LIMIT).unwrap()
+}
+
+fn default_lines() -> usize {
+    5
+}
+
+fn default_backoff() -> NonZeroU32 {
+    NonZeroU32::new(1).unwrap()
+}
+
+fn default_max_cooldown() -> NonZeroU64 {
+    NonZeroU64::new(86400).unwrap()
+}
+
+impl AuthFailure {
+    /// The built-in rule of `kind`, named like it except for `system`.
+    pub fn builtin(kind: Auth) -> Self {
+        let (name, pattern, notify, key): (_, _, _, &[&str]) = match kind {
+            Auth::Sudo => (
+                "sudo",
+                pam_unix("sudo"),
+                "sudo bashing detected",
+                &["user", "rhost"],
+            ),
+            Auth::System => (
+                "system-auth",
+                pam_unix("system-auth"),
+                "system-auth bashing detected",
+                &["user", "rhost"],
+            ),
+            Auth::SshdFailedPassword => (
+                "sshd-failed-password",
+                sshd(
+                    r"Failed password for (?:invalid user )?(?P<user>.*?) from (?P<rhost>\S+) port \d+(?: ssh2)?",
+                ),
+                "ssh password bashing detected",
+                &["rhost"],
+            ),
+            Auth::SshdInvalidUser => (
+                "sshd-invalid-user",
+                sshd(r"Invalid user (?P<user>.*?) from (?P<rhost>\S+) port \d+"),
+                "ssh user probing detected",
+                &["rhost"],
+            ),
+            Auth::SshdPreauth => (
+                "sshd-preauth",
+                sshd(
+                    r"Connection closed by authenticating user (?P<user>.*?) (?P<rhost>\S+) port \d+ \[preauth\]",
+                ),
+                "ssh preauth disconnects detected",
+                &["rhost"],
+            ),
+            Auth::SshdMaxAuthTries => (
+                "sshd-max-auth-tries",
+                sshd(
+                    r"maximum authentication attempts exceeded for (?:invalid user )?(?P<user>.*?) from (?P<rhost>\S+) port \d+(?: ssh2)?(?: \[preauth\])?",
+                ),
+                "ssh maximum authentication attempts exceeded",
+                &["rhost"],
+            ),
+        };
+
+        AuthFailure {
+            name: name.to_string(),
+            kind: Some(kind),
+            pattern,
+            notify: Template::from(notify),
+            key: key.iter().map(|field| field.to_string()).collect(),
+            threshold: default_threshold(),
+            window: default_window(),
+            lines: default_lines(),
+            notifiers: None,
+            backoff: default_backoff(),
+            max_cooldown: default_max_cooldown(),
+            escalate: vec![],
+            ban: None,
+            ipv4_prefix: None,
+            ipv6_prefix: None,
+            allow: Allowlist::default(),
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window.get())
+    }
+
+    /// Cooldown after an alert for a key that had `alerts` alerts before.
+    pub fn cooldown(&self, alerts: usize) -> Duration {
+        let factor = u64::from(self.backoff.get())
+            .checked_pow(alerts.min(u32::MAX as usize) as u32)
+            .unwrap_or(u64::MAX);
+        Duration::from_secs(
+            self.window
+                .get()
+                .saturating_mul(factor)
+                .min(self.max_cooldown.get()),
+        )
+    }
+
+    /// Index of the escalation step for an alert of a key that had `alerts`
+    /// alerts before, `None` while not escalated.
+    pub fn escalation(&self, alerts: usize) -> Option<usize> {
+        self.escalate
+            .iter()
+            .enumerate()
+            .filter(|(_, step)| alerts >= step.after)
+            .max_by_key(|(_, step)| step.after)
+            .map(|(i, _)| i)
+    }
+
+    /// Check that every key field is a capture group of the pattern, and
+    /// that rules with bans count failures per `rhost`.
+    pub fn validate(&self) -> Result<(), String> {
+        let has_group = |field: &str| self.pattern.capture_names().flatten().any(|n| n == field);
+        for field in &self.key {
+            if !has_group(field) {
+                return Err(format!(
+                    "rule `{}`: key field `{}` is not a capture group of the pattern",
+                    self.name, field
+                ));
+            }
+        }
+        // counted together, the host reaching the threshold need not be
+        // the one that failed most
+        if self.ban.is_some() && !self.key.iter().any(|field| field == "rhost") {
+            return Err(format!(
+                "rule `{}`: bans need `rhost` in the key",
+                self.name
+            ));
+        }
+        if self.ipv4_prefix.is_some_and(|len| len > 32)
+            || self.ipv6_prefix.is_some_and(|len| len > 128)
+        {
+            return Err(format!("rule `{}`: prefix length out of range", self.name));
+        }
+
+        Ok(())
+    }
+
+    /// Aggregation key of `failure`, e.g. `user=root rhost=10.0.0.1`.
+    pub fn key(&self, failure: &Failure) -> String {
+        self.key
+            .iter()
+            .map(|field| {
+                format!(
+                    "{}={}",
+                    field,
+                    failure.fields.get(field).map_or("", String::as_str)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Match `line` against the rule, collecting all named captures that
+    /// took part in the match. An `rhost` that is an IP address is
+    /// normalized and grouped by prefix.
+    pub fn matches(&self, line: &str) -> Option<Failure> {
+        let caps = self.pattern.captures(line)?;
+        let mut fields: BTreeMap<String, String> = self
+            .pattern
+            .capture_names()
+            .flatten()
+            .filter_map(|name| {
+                caps.name(name)
+                    .map(|m| (name.to_string(), m.as_str().to_string()))
+            })
+            .collect();
+        let addr = fields.get("rhost").and_then(|rhost| rhost_addr(rhost));
+        if let Some(addr) = addr {
+            fields.insert("rhost".to_string(), self.rhost_net(addr).to_string());
+        }
+
+        Some(Failure {
+            line: line.trim_end().to_string(),
+            fields,
+            addr,
+        })
+    }
+
+    /// `addr` as it is, or as prefix if the rule groups addresses.
+    fn rhost_net(&self, addr: IpAddr) -> Net {
+        let prefix_len = if addr.is_ipv4() {
+            self.ipv4_prefix
+        } else {
+            self.ipv6_prefix
+        };
+        prefix_len
+            .and_then(|len| Net::new(addr, len))
+            .unwrap_or_else(|| Net::from(addr))
+    }
+}
+
+/// `rhost` as normalized address, `None` for host names.
+fn rhost_addr(rhost: &str) -> Option<IpAddr> {
+    let addr = rhost.trim_start_matches('[').trim_end_matches(']');
+    addr.parse().ok().map(net::normalize)
+}
+
+/// `message` as logged by sshd, or its per-connection `sshd-session`.
+///
+/// User names are chosen by the client and may contain spaces or look like
+/// the rest of the message, `message` has to match up to the end of the
+/// line so the `rhost` sshd logs last is the one captured.
+fn sshd(message: &str) -> Regex {
+    Regex::new(&format!(
+        r"sshd(?:-session)?\[\d+\]: (?:error: )?{}\s*$",
+        message
+    ))
+    .unwrap()
+}
+
+fn pam_unix(service: &str) -> Regex {
+    Regex::new(&format!(
+        r"pam_unix\({}:auth\): authentication failure; {}",
+        regex::escape(service),
+        PAM_UNIX_FIELDS
+    ))
+    .unwrap()
+}
+
+/// A single line matched by an `AuthFailure` rule.
+#[derive(Clone, Debug)]
+pub struct Failure {
+    pub line: String,
+    pub fields: BTreeMap<String, String>,
+    /// `rhost` if it is an address, before grouping by prefix.
+    pub addr: Option<IpAddr>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rhost(kind: Auth, line: &str) -> Option<String> {
+        AuthFailure::builtin(kind)
+            .matches(line)
+            .map(|failure| failure.fields["rhost"].clone())
+    }
+
+    #[test]
+    fn cooldown_backoff() {
+        let mut rule = AuthFailure::builtin(Auth::Sudo);
+        assert_eq!(rule.cooldown(0), Duration::from_secs(300));
+        assert_eq!(rule.cooldown(5), Duration::from_secs(300));
+        rule.backoff = NonZeroU32::new(2).unwrap();
+        rule.max_cooldown = NonZeroU64::new(3600).unwrap();
+        assert_eq!(rule.cooldown(0), Duration::from_secs(300));
+        assert_eq!(rule.cooldown(1), Duration::from_secs(600));
+        assert_eq!(rule.cooldown(3), Duration::from_secs(2400));
+        assert_eq!(rule.cooldown(4), Duration::from_secs(3600));
+        assert_eq!(rule.cooldown(usize::MAX), Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn escalation_steps() {
+        let mut rule = AuthFailure::builtin(Auth::Sudo);
+        assert_eq!(rule.escalation(10), None);
+        let step = |after, severity| Escalation {
+            after,
+            severity,
+            notifiers: None,
+        };
+        // not necessarily in order
+        rule.escalate = vec![step(5, Severity::Crit), step(2, Severity::Err)];
+        assert_eq!(rule.escalation(0), None);
+        assert_eq!(rule.escalation(1), None);
+        assert_eq!(rule.escalation(2), Some(1));
+        assert_eq!(rule.escalation(4), Some(1));
+        assert_eq!(rule.escalation(5), Some(0));
+        assert_eq!(rule.escalation(100), Some(0));
+    }
+
+    #[test]
+    fn ban_needs_rhost_key() {
+        let mut rule = AuthFailure::builtin(Auth::SshdFailedPassword);
+        rule.ban = NonZeroU64::new(3600);
+        assert!(rule.validate().is_ok());
+        rule.key = vec!["user".to_string()];
+        assert!(rule.validate().is_err());
+    }
+
+    #[test]
+    fn sshd_user_cannot_fake_rhost() {
+        let line = "Mar  1 10:00:00 host sshd[42]: Failed password for invalid user x from 10.0.0.1 port 1 from 203.0.113.9 port 4242 ssh2\n";
+        assert_eq!(
+            rhost(Auth::SshdFailedPassword, line).as_deref(),
+            Some("203.0.113.9")
+        );
+    }
+
+    #[test]
+    fn sshd_user_with_space() {
+        let line = "Mar  1 10:00:00 host sshd[42]: Invalid user a b from 198.51.100.7 port 4242\n";
+        assert_eq!(
+            rhost(Auth::SshdInvalidUser, line).as_deref(),
+            Some("198.51.100.7")
+        );
+    }
+
+    #[test]
+    fn sshd_invalid_user_needs_port() {
+        let line = "Mar  1 10:00:00 host sshd[42]: Invalid user x from 10.0.0.1\n";
+        assert_eq!(rhost(Auth::SshdInvalidUser, line), None);
+    }
+
+    #[test]
+    fn sshd_max_auth_tries() {
+        let line = "Mar  1 10:00:00 host sshd[42]: error: maximum authentication attempts exceeded for root from 192.0.2.1 port 4242 ssh2 [preauth]\n";
+        assert_eq!(
+            rhost(Auth::SshdMaxAuthTries, line).as_deref(),
+            Some("192.0.2.1")
+        );
+    }
+}
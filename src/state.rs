@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const STATE_PATH: &str = "/var/lib/watch/state.toml";
+
+/// Read offsets of all watched files, kept across restarts.
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct State {
+    /// Number of inotify queue overflows, across restarts.
+    #[serde(default)]
+    pub overflows: u64,
+    /// Failures dropped by allowlists per rule, across restarts.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ignored: BTreeMap<String, u64>,
+    #[serde(default, rename = "file")]
+    pub files: Vec<FileState>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct FileState {
+    pub path: PathBuf,
+    pub dev: u64,
+    pub ino: u64,
+    pub pos: u64,
+}
+
+impl State {
+    /// Read the state file, a missing file is an empty state.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, io::Error> {
+        let path = path.as_ref();
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(State::default()),
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("{}: {}", path.display(), e),
+                ))
+            }
+        };
+        toml::from_str(&content).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })
+    }
+
+    /// Replace the state file atomically.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), io::Error> {
+        let path = path.as_ref();
+        let content = toml::to_string(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+        write_atomic(path, &content)
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&FileState> {
+        self.files.iter().find(|f| f.path == path)
+    }
+}
+
+/// Replace `path` with `content` by writing `<path>.tmp` and renaming it,
+/// creating missing parent directories.
+pub fn write_atomic(path: &Path, content: &str) -> Result<(), io::Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, path)
+}
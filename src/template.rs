@@ -0,0 +1,150 @@
+use serde::{Deserialize, Deserializer};
+
+use crate::notifier::{rfc3339, Alert};
+
+/// Text with `{name}` placeholders filled in from an alert.
+///
+/// Known names are `rule`, `kind`, `message`, `severity`, `key`, `count`,
+/// `window` (as in `5m`), `first_seen`, `last_seen` and `lines`, anything
+/// else is looked up in the captured fields and left empty if there is no
+/// such field. Braces not around a name are kept as they are, so JSON
+/// templates need no escaping.
+#[derive(Clone, Debug)]
+pub struct Template(String);
+
+impl<'de> Deserialize<'de> for Template {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer).map(Template)
+    }
+}
+
+impl From<&str> for Template {
+    fn from(s: &str) -> Self {
+        Template(s.to_string())
+    }
+}
+
+impl Template {
+    /// Fill in the placeholders, each value passed through `escape`.
+    pub fn render<F>(&self, alert: &Alert, escape: F) -> String
+    where
+        F: Fn(&str) -> String,
+    {
+        let mut out = String::with_capacity(self.0.len());
+        let mut rest = &self.0[..];
+
+        while let Some(start) = rest.find('{') {
+            out.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let name = rest[1..]
+                .find('}')
+                .map(|end| &rest[1..=end])
+                .filter(|name| {
+                    !name.is_empty()
+                        && name
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                });
+            match name {
+                Some(name) => {
+                    out.push_str(&escape(&value(alert, name)));
+                    rest = &rest[name.len() + 2..];
+                }
+                None => {
+                    out.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        out.push_str(rest);
+
+        out
+    }
+}
+
+fn value(alert: &Alert, name: &str) -> String {
+    match name {
+        "rule" => alert.rule.clone(),
+        "kind" => alert.kind.map(|k| k.to_string()).unwrap_or_default(),
+        "message" => alert.message.clone(),
+        "severity" => alert.severity.map(|s| s.to_string()).unwrap_or_default(),
+        "key" => alert.key.clone(),
+        "count" => alert.count.to_string(),
+        "window" => duration(alert.window),
+        "first_seen" => rfc3339(alert.first_seen),
+        "last_seen" => rfc3339(alert.last_seen),
+        "lines" => alert.lines.join("\n"),
+        _ => alert.fields.get(name).cloned().unwrap_or_default(),
+    }
+}
+
+/// `secs` in the largest unit that divides it, e.g. `5m` for 300.
+fn duration(secs: u64) -> String {
+    match secs {
+        0 => "0s".to_string(),
+        s if s % 3600 == 0 => format!("{}h", s / 3600),
+        s if s % 60 == 0 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+/// Escape `s` for use inside a JSON string.
+pub fn json_escape(s: &str) -> String {
+    let quoted = serde_json::to_string(s).unwrap_or_default();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notifier::tests;
+
+    /// The shared alert, with a message that needs escaping and an `rhost`.
+    fn alert() -> Alert {
+        let mut alert = tests::alert();
+        alert.message = "say \"hi\"\nbye".to_string();
+        alert
+            .fields
+            .insert("rhost".to_string(), "192.0.2.1".to_string());
+        alert
+    }
+
+    fn render(template: &str) -> String {
+        Template::from(template).render(&alert(), str::to_string)
+    }
+
+    #[test]
+    fn names_and_fields() {
+        assert_eq!(
+            render("{rule}: {count} in {window} from {rhost}{missing}"),
+            "sudo: 3 in 5m from 192.0.2.1"
+        );
+        assert_eq!(render("{first_seen}"), "1970-01-01T00:00:00Z");
+    }
+
+    #[test]
+    fn nested_braces() {
+        assert_eq!(render("{{rule}}"), "{sudo}");
+        assert_eq!(render("{a {rule} b}"), "{a sudo b}");
+    }
+
+    #[test]
+    fn unmatched_braces() {
+        assert_eq!(render("{rule"), "{rule");
+        assert_eq!(render("rule}"), "rule}");
+        assert_eq!(render("{}{"), "{}{");
+        assert_eq!(render("{rule}{"), "sudo{");
+    }
+
+    #[test]
+    fn json_escaping() {
+        let body = Template::from(r#"{"text": "{message}", "count": {count}}"#)
+            .render(&alert(), json_escape);
+        assert_eq!(body, r#"{"text": "say \"hi\"\nbye", "count": 3}"#);
+        let value: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(value["text"], "say \"hi\"\nbye");
+    }
+}
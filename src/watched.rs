@@ -0,0 +1,459 @@
+use inotify::{Inotify, WatchDescriptor, WatchMask};
+use regex::Regex;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::notifier::{self, Alert, Notifier};
+use crate::state::FileState;
+use crate::{notify, FailureMap};
+
+/// How long to wait for the rest of a line before matching it anyway.
+const LINE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a rotated file is still read after it was last written to, if
+/// the new file is not written to first.
+const ROTATED_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Watched {
+    pub path: PathBuf,
+    pub file: OsString,
+    pub dir: PathBuf,
+    pub pos: u64,
+    /// Device and inode of the file `pos` refers to.
+    pub dev: u64,
+    pub ino: u64,
+    /// Kept open between events, positioned after `linebuffer`. Also allows reading
+    /// the file to its end after it was rotated away.
+    handle: Option<BufReader<File>>,
+    /// Bytes read since the last newline, not yet part of `pos`.
+    linebuffer: Vec<u8>,
+    /// When the incomplete line in `linebuffer` was first seen.
+    line_time: Option<Instant>,
+    pub failures: Vec<FailureMap>,
+    /// Where alerts about the file itself go, those of all its rules.
+    notifiers: Vec<Rc<dyn Notifier>>,
+    /// Lines logged by our own syslog notifiers, never matched.
+    ignore: Option<Regex>,
+    /// Watch on the log file itself, `None` while the file does not exist.
+    /// Stays on the rotated file while it is still written to.
+    pub wd: Option<WatchDescriptor>,
+    /// Watch on the new file while the rotated one is still read.
+    pub pending: Option<WatchDescriptor>,
+    /// When the rotated file was last written to, while `pending`.
+    rotated_time: Option<Instant>,
+    /// Watch on the parent directory, shared by all files in that directory.
+    pub dir_wd: Option<WatchDescriptor>,
+}
+
+impl Watched {
+    pub fn new<P: AsRef<Path>>(
+        f: P,
+        failures: Vec<FailureMap>,
+        notifiers: Vec<Rc<dyn Notifier>>,
+        ignore: Option<Regex>,
+    ) -> Self {
+        let f = f.as_ref();
+        Watched {
+            path: f.to_path_buf(),
+            file: f
+                .file_name()
+                .unwrap_or_else(|| OsStr::new(""))
+                .to_os_string(),
+            dir: f.parent().unwrap_or_else(|| Path::new("/")).to_path_buf(),
+            pos: 0,
+            dev: 0,
+            ino: 0,
+            handle: None,
+            linebuffer: vec![],
+            line_time: None,
+            failures,
+            notifiers,
+            ignore,
+            wd: None,
+            pending: None,
+            rotated_time: None,
+            dir_wd: None,
+        }
+    }
+
+    pub fn set_pos(&mut self, n: u64) {
+        self.pos = n;
+    }
+
+    /// Add the directory watch and, if the file already exists, the file
+    /// watch.
+    ///
+    /// Reading resumes at the `saved` offset if the file is still the same
+    /// one. If it was rotated since, the rest of the rotated file is read
+    /// first and the new file from the start. Without saved state existing
+    /// content is skipped.
+    pub fn add_watches(
+        &mut self,
+        inotify: &mut Inotify,
+        saved: Option<&FileState>,
+    ) -> Result<(), io::Error> {
+        self.dir_wd =
+            Some(inotify.add_watch(&self.dir, WatchMask::CREATE | WatchMask::MOVED_FROM)?);
+
+        if self.path.is_file() {
+            let meta = fs::metadata(&self.path)?;
+            match saved {
+                Some(s) if (s.dev, s.ino) == (meta.dev(), meta.ino()) => self.set_pos(s.pos),
+                Some(s) => {
+                    if let Some(rotated) = self.find_rotated(s)? {
+                        let mut reader = BufReader::new(File::open(rotated)?);
+                        reader.seek(SeekFrom::Start(s.pos))?;
+                        self.scan(reader)?;
+                        self.flush()?;
+                    }
+                    self.set_pos(0);
+                }
+                None => self.set_pos(meta.len()),
+            }
+            self.wd =
+                Some(inotify.add_watch(&self.path, WatchMask::MODIFY | WatchMask::MOVE_SELF)?);
+            self.open()?;
+            self.tail()?;
+        }
+
+        Ok(())
+    }
+
+    /// Look for the file `saved` refers to among the rotated files next to
+    /// this one (`auth.log.1`, `auth.log-20200301`, ...).
+    fn find_rotated(&self, saved: &FileState) -> Result<Option<PathBuf>, io::Error> {
+        let prefix = self.file.to_string_lossy();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if name == self.file || !name.to_string_lossy().starts_with(&*prefix) {
+                continue;
+            }
+            let meta = entry.metadata()?;
+            if (meta.dev(), meta.ino()) == (saved.dev, saved.ino) {
+                return Ok(Some(entry.path()));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Current read offset for the state file, `None` while there is no file
+    /// open.
+    pub fn state(&self) -> Option<FileState> {
+        self.handle.as_ref().map(|_| FileState {
+            path: self.path.clone(),
+            dev: self.dev,
+            ino: self.ino,
+            pos: self.pos,
+        })
+    }
+
+    /// Keep the file at `path` open at `pos` and remember which one it is.
+    fn open(&mut self) -> Result<(), io::Error> {
+        let mut f = File::open(&self.path)?;
+        let meta = f.metadata()?;
+        self.dev = meta.dev();
+        self.ino = meta.ino();
+        f.seek(SeekFrom::Start(self.pos))?;
+        self.handle = Some(BufReader::new(f));
+
+        Ok(())
+    }
+
+    /// The file was (re)created. The rotated file is read on until the new
+    /// file is written to, or for `ROTATED_TIMEOUT` after its last write:
+    /// with logrotate's `create` the syslog daemon keeps writing to it until
+    /// it is told to reopen its files.
+    pub fn created(&mut self, inotify: &mut Inotify) -> Result<(), io::Error> {
+        if self.pending.is_some() {
+            // rotated again before the previous new file was written to
+            self.switch(inotify)?;
+        }
+        let wd = inotify.add_watch(&self.path, WatchMask::MODIFY | WatchMask::MOVE_SELF)?;
+        if self.handle.is_none() {
+            self.wd = Some(wd);
+            self.set_pos(0);
+            self.open()?;
+            return self.tail();
+        }
+        self.pending = Some(wd);
+        self.rotated_time = Some(Instant::now());
+        self.tail()
+    }
+
+    /// Finish the rotated file and drop its watch, then read the new file
+    /// from the start. The new file takes over even if draining the rotated
+    /// one fails.
+    pub fn switch(&mut self, inotify: &mut Inotify) -> Result<(), io::Error> {
+        let wd = match self.pending.take() {
+            Some(wd) => wd,
+            None => return Ok(()),
+        };
+        self.rotated_time = None;
+        let drained = self.tail().and_then(|()| self.flush().map(|_| ()));
+        if let Some(old) = self.wd.replace(wd) {
+            // fails if the rotated file is gone already, its watch with it
+            let _ = inotify.rm_watch(old);
+        }
+        self.set_pos(0);
+        self.linebuffer.clear();
+        self.line_time = None;
+        // nothing to read until the file is created again if this fails
+        self.handle = None;
+        let opened = self.open().and_then(|()| self.tail());
+
+        drained.and(opened)
+    }
+
+    /// Time left until the rotated file is given up on.
+    pub fn rotated_timeout(&self) -> Option<Duration> {
+        self.rotated_time
+            .map(|t| ROTATED_TIMEOUT.checked_sub(t.elapsed()).unwrap_or_default())
+    }
+
+    /// Switch to the new file once the rotated one was quiet for
+    /// `ROTATED_TIMEOUT`.
+    pub fn switch_stale(&mut self, inotify: &mut Inotify) -> Result<(), io::Error> {
+        if self.rotated_timeout() == Some(Duration::from_secs(0)) {
+            self.switch(inotify)?;
+        }
+
+        Ok(())
+    }
+
+    /// Catch up after inotify events were lost: renew the watches and
+    /// handle whatever happened to the file in the meantime.
+    pub fn rescan(&mut self, inotify: &mut Inotify) -> Result<(), io::Error> {
+        self.dir_wd =
+            Some(inotify.add_watch(&self.dir, WatchMask::CREATE | WatchMask::MOVED_FROM)?);
+        // writes to the new file may have been missed
+        self.switch(inotify)?;
+
+        match fs::metadata(&self.path) {
+            Ok(meta)
+                if self.handle.is_some() && (meta.dev(), meta.ino()) == (self.dev, self.ino) =>
+            {
+                self.wd =
+                    Some(inotify.add_watch(&self.path, WatchMask::MODIFY | WatchMask::MOVE_SELF)?);
+                self.tail()
+            }
+            // rotated, or created while there was no file
+            Ok(_) => self.created(inotify),
+            // moved away and not yet recreated
+            Err(e) if e.kind() == io::ErrorKind::NotFound => self.tail(),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The file was moved away, read what was written to it before the
+    /// rename. Lines still written to the old file are read on its own
+    /// events until the new one takes over.
+    pub fn rotated(&mut self) -> Result<(), io::Error> {
+        self.tail()
+    }
+
+    /// Is `name` in directory watch `wd` this file?
+    pub fn is_named(&self, wd: &WatchDescriptor, name: Option<&OsStr>) -> bool {
+        self.dir_wd.as_ref() == Some(wd) && name == Some(self.file.as_os_str())
+    }
+
+    /// Read everything appended to the open file since the last call, whatever
+    /// name it has by now, and run it through the rules.
+    pub fn tail(&mut self) -> Result<(), io::Error> {
+        let mut reader = match self.handle.take() {
+            Some(reader) => reader,
+            None => return Ok(()),
+        };
+        let len = reader.get_ref().metadata()?.len();
+        if len < self.pos + self.linebuffer.len() as u64 {
+            // copytruncate, or someone wiping the log
+            let now = SystemTime::now();
+            let alert = Alert {
+                rule: "log-truncated".to_string(),
+                kind: None,
+                message: format!(
+                    "log truncated: {} shrank from {} to {} bytes",
+                    self.path.display(),
+                    self.pos,
+                    len
+                ),
+                threshold: false,
+                severity: None,
+                key: self.path.display().to_string(),
+                fields: Default::default(),
+                count: 1,
+                window: 0,
+                first_seen: now,
+                last_seen: now,
+                lines: vec![],
+            };
+            notifier::send(&self.notifiers, &alert);
+            reader.seek(SeekFrom::Start(0))?;
+            self.set_pos(0);
+            self.linebuffer.clear();
+            self.line_time = None;
+        }
+        let buffered = self.linebuffer.len();
+        let result = self.scan(&mut reader);
+        self.handle = Some(reader);
+        let read = result?;
+        if self.pending.is_some() && (read > 0 || self.linebuffer.len() != buffered) {
+            self.rotated_time = Some(Instant::now());
+        }
+        self.set_pos(self.pos + read);
+
+        Ok(())
+    }
+
+    /// Time left until an incomplete line gets matched without its newline.
+    pub fn line_timeout(&self) -> Option<Duration> {
+        self.line_time
+            .map(|t| LINE_TIMEOUT.checked_sub(t.elapsed()).unwrap_or_default())
+    }
+
+    /// Match an incomplete line that waited longer than `LINE_TIMEOUT`.
+    pub fn flush_stale(&mut self) -> Result<(), io::Error> {
+        if self.line_timeout() == Some(Duration::from_secs(0)) {
+            let flushed = self.flush()?;
+            self.set_pos(self.pos + flushed);
+        }
+
+        Ok(())
+    }
+
+    /// Match whatever is left in `linebuffer`, returns its length.
+    fn flush(&mut self) -> Result<u64, io::Error> {
+        let len = self.linebuffer.len() as u64;
+        if len > 0 {
+            self.check_line()?;
+        }
+
+        Ok(len)
+    }
+
+    /// Run every complete line from `reader` through the rules, returns the
+    /// number of bytes of those lines. A trailing line without newline stays
+    /// in `linebuffer` until the rest of it is read.
+    fn scan<R: BufRead>(&mut self, mut reader: R) -> Result<u64, io::Error> {
+        let mut total = 0;
+        loop {
+            let bytes_read = reader.read_until(b'\n', &mut self.linebuffer)?;
+            if bytes_read == 0 {
+                break;
+            } else if self.linebuffer.last() != Some(&b'\n') {
+                self.line_time.get_or_insert_with(Instant::now);
+                break;
+            } else {
+                total += self.linebuffer.len() as u64;
+                self.check_line()?;
+            }
+        }
+
+        Ok(total)
+    }
+
+    /// Run `linebuffer` through the rules and clear it.
+    fn check_line(&mut self) -> Result<(), io::Error> {
+        let line = String::from_utf8_lossy(&self.linebuffer[..]).into_owned();
+        self.linebuffer.clear();
+        self.line_time = None;
+
+        if self.ignore.as_ref().is_some_and(|re| re.is_match(&line)) {
+            return Ok(());
+        }
+
+        for map in &mut self.failures {
+            if let Some(failure) = map.auth_failure.matches(&line) {
+                if map.auth_failure.allow.allows(&failure) {
+                    map.ignored += 1;
+                    continue;
+                }
+                notify(map, &failure)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notifier::tests::Recorder;
+    use crate::rule::{Auth, AuthFailure};
+    use std::env;
+    use std::io::Cursor;
+    use std::num::NonZeroUsize;
+    use std::process;
+
+    const LINE: &str = "sudo: pam_unix(sudo:auth): authentication failure; logname=bob uid=1000 euid=0 tty=/dev/pts/0 ruser=bob rhost=  user=bob\n";
+
+    /// A file with the `sudo` rule alerting on every failure.
+    fn watched(path: &Path) -> (Watched, Rc<Recorder>) {
+        let recorder = Rc::new(Recorder::default());
+        let mut rule = AuthFailure::builtin(Auth::Sudo);
+        rule.threshold = NonZeroUsize::new(1).unwrap();
+        let notifiers: Vec<Rc<dyn Notifier>> = vec![recorder.clone()];
+        let map = FailureMap::new(rule, notifiers.clone(), vec![], None, None);
+        (Watched::new(path, vec![map], notifiers, None), recorder)
+    }
+
+    #[test]
+    fn partial_line_joined() {
+        let (mut watch, recorder) = watched(Path::new("/var/log/auth.log"));
+        let (start, rest) = LINE.split_at(40);
+        assert_eq!(watch.scan(Cursor::new(start)).unwrap(), 0);
+        assert!(watch.line_timeout().is_some());
+        assert!(recorder.0.borrow().is_empty());
+
+        assert_eq!(watch.scan(Cursor::new(rest)).unwrap(), LINE.len() as u64);
+        assert!(watch.line_timeout().is_none());
+        let alerts = recorder.0.borrow();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].lines, vec![LINE.trim_end().to_string()]);
+    }
+
+    #[test]
+    fn partial_line_flushed_after_timeout() {
+        let (mut watch, recorder) = watched(Path::new("/var/log/auth.log"));
+        let line = LINE.trim_end();
+        assert_eq!(watch.scan(Cursor::new(line)).unwrap(), 0);
+        watch.flush_stale().unwrap();
+        assert!(recorder.0.borrow().is_empty());
+
+        watch.line_time = Instant::now().checked_sub(LINE_TIMEOUT);
+        assert_eq!(watch.line_timeout(), Some(Duration::from_secs(0)));
+        watch.flush_stale().unwrap();
+        assert_eq!(recorder.0.borrow().len(), 1);
+        assert_eq!(watch.pos, line.len() as u64);
+        assert!(watch.line_timeout().is_none());
+    }
+
+    #[test]
+    fn truncated_file_read_from_start() {
+        let path = env::temp_dir().join(format!("watch-truncated-{}.log", process::id()));
+        fs::write(&path, format!("{}{}", LINE, &LINE[..40])).unwrap();
+        let (mut watch, recorder) = watched(&path);
+        watch.open().unwrap();
+        watch.tail().unwrap();
+        assert_eq!(watch.pos, LINE.len() as u64);
+
+        // as long as `pos`, but shorter than that and the buffered partial
+        // line
+        let line = LINE.replace("bob", "eve");
+        fs::write(&path, &line).unwrap();
+        watch.tail().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(watch.pos, line.len() as u64);
+        let alerts = recorder.0.borrow();
+        let rules: Vec<_> = alerts.iter().map(|a| a.rule.as_str()).collect();
+        assert_eq!(rules, vec!["sudo", "log-truncated", "sudo"]);
+        assert_eq!(alerts[2].lines, vec![line.trim_end().to_string()]);
+    }
+}